@@ -0,0 +1,551 @@
+use async_std::io::BufReader;
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use async_std::prelude::*;
+use async_std::task;
+use openssl::{hash, memcmp};
+use sqlite::ConnectionThreadSafe as Db;
+use std::fmt::Write as _;
+use std::io;
+use std::os::unix::fs::{FileTypeExt as _, PermissionsExt as _};
+use std::path::Path;
+
+use crate::cidr::Cidr;
+use crate::config::Config;
+use crate::db::{self, BanKind, SERVER_USER_ID};
+use crate::log;
+use crate::rooms;
+use crate::sql::SqlSession;
+
+const MAX_LOGIN_ATTEMPTS: u32 = 3;
+
+/// registros mostrados por `/audit`
+const AUDIT_PAGE: u32 = 100;
+
+/// Minutos antes da manutenção em que o aviso é repetido.
+const MAINTENANCE_REMINDERS: [i64; 6] = [60, 30, 15, 5, 1, 0];
+
+const HELP: &str = "\
+/users                  list connected users
+/rooms                  list all rooms
+/kick <user>            disconnect a user
+/ban <user> <room>      kick and ban a user from a room
+/close <room>           close a room
+/persist <room> on|off  keep a room open after its admin disconnects
+/say <room>[,<room>...] <text>
+                        send a message to rooms as `server`
+/announce <text>        send an announcement to every connected user
+/motd [text]            set the message of the day, clear it if empty
+/maintenance <minutes> <text>
+                        schedule a maintenance notice, repeated as it nears
+/maintenance cancel     cancel the scheduled maintenance
+/gban user <name> <duration|perm> [reason]
+/gban ip <addr|cidr> <duration|perm> [reason]
+                        ban from the whole server, duration like 30m, 12h, 7d
+/gbans                  list active server bans
+/gunban <id>            lift a server ban
+/audit [room]           recent moderation and console actions, optionally for one room
+/log [spec]             show or change log levels, e.g. `debug`, `db=debug,net=warn`
+/log format json|text   switch the log output format
+/log payloads on|off    log message contents, for debugging only
+/sql <query>            run one SQL statement (changes are rolled back)
+/sql-write on|off       allow writes: /sql previews them, /commit applies
+/commit                 apply the previewed write to the live database
+/rollback               discard the previewed write
+";
+
+/// Console do operador na entrada padrão do servidor.
+pub async fn console(db: &'static Db) {
+    let mut line = String::new();
+    let mut out = String::new();
+    let mut sql = SqlSession::default();
+    loop {
+        print!("> ");
+        async_std::io::stdout().flush().await.unwrap();
+        line.clear();
+        match async_std::io::stdin().read_line(&mut line).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        out.clear();
+        run(db, &mut sql, line.trim(), &mut out);
+        print!("{}", out);
+    }
+}
+
+/// Console do operador num socket Unix, uma sessão por conexão.
+pub async fn serve(db: &'static Db, config: &'static Config) {
+    let Some(path) = &config.admin_socket else {
+        return;
+    };
+    if let Err(err) = remove_socket(path) {
+        error!(Admin, "cannot replace {:?}: {}", path, err);
+        return;
+    }
+    // o socket já nasce acessível só ao dono, o modo configurado vem depois
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path).await;
+    unsafe { libc::umask(umask) };
+    let listener = match listener {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!(Admin, "cannot bind admin socket {:?}: {}", path, err);
+            return;
+        }
+    };
+    // o acesso ao console é controlado pelas permissões do arquivo
+    let mode = std::fs::Permissions::from_mode(config.admin_socket_mode);
+    if let Err(err) = std::fs::set_permissions(path, mode) {
+        error!(Admin, "cannot chmod admin socket {:?}: {}", path, err);
+        return;
+    }
+    info!(
+        Admin,
+        "admin console on {:?} (mode {:o})", path, config.admin_socket_mode
+    );
+
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let Ok(stream) = stream else { continue };
+        task::spawn(session(db, config, stream));
+    }
+}
+
+/// Apaga o socket deixado em `path` por uma execução anterior. Qualquer outra coisa no
+/// caminho é um erro de configuração e fica onde está.
+pub fn remove_socket(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "exists and is not a socket",
+        )),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+async fn session(db: &'static Db, config: &'static Config, stream: UnixStream) {
+    info!(Admin, "admin session opened");
+    let mut reader = BufReader::new(stream.clone());
+    let mut writer = stream;
+    let mut line = String::new();
+    let mut out = String::new();
+    let mut logged_in = config.admin_secret.is_none();
+    let mut login_attempts = 0;
+    let mut sql = SqlSession::default();
+
+    if !logged_in {
+        out.push_str("login required: /login <secret>\n");
+    }
+    loop {
+        out.push_str("> ");
+        if writer.write_all(out.as_bytes()).await.is_err() {
+            break;
+        }
+        line.clear();
+        match reader.read_line(&mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        out.clear();
+        if logged_in {
+            run(db, &mut sql, line.trim(), &mut out);
+            continue;
+        }
+        let secret = line.trim().strip_prefix("/login ").unwrap_or("");
+        if check_secret(config.admin_secret.as_deref().unwrap_or(""), secret.trim()) {
+            logged_in = true;
+            out.push_str("logged in\n");
+            continue;
+        }
+        login_attempts += 1;
+        warn!(Admin, "admin login failed");
+        if login_attempts >= MAX_LOGIN_ATTEMPTS {
+            let _ = writer.write_all(b"login failed\n").await;
+            break;
+        }
+        out.push_str("login failed\n");
+    }
+    info!(Admin, "admin session closed");
+}
+
+/// Compara os hashes para que o tempo não dependa do conteúdo nem do tamanho.
+fn check_secret(expected: &str, given: &str) -> bool {
+    let digest = hash::MessageDigest::sha256();
+    let (Ok(expected), Ok(given)) = (
+        hash::hash(digest, expected.as_bytes()),
+        hash::hash(digest, given.as_bytes()),
+    ) else {
+        return false;
+    };
+    memcmp::eq(&expected, &given)
+}
+
+/// Executa uma linha do console, escrevendo a resposta em `out`.
+pub fn run(db: &'static Db, sql: &mut SqlSession, line: &str, out: &mut String) {
+    if let Err(err) = execute(db, sql, line, out) {
+        error!(Admin, "{}", err);
+        let _ = writeln!(out, "database error: {}", err);
+    }
+}
+
+fn execute(
+    db: &'static Db,
+    sql: &mut SqlSession,
+    line: &str,
+    out: &mut String,
+) -> db::DbResult<()> {
+    if line.is_empty() {
+        return Ok(());
+    }
+    let mut split = line.split_whitespace();
+    match (split.next(), split.next(), split.next()) {
+        (Some("/users"), None, _) => {
+            let users = db::User::get_all(db)?;
+            for name in &users {
+                let _ = writeln!(out, "{}", name);
+            }
+            let _ = writeln!(out, "({} users)", users.len());
+        }
+        (Some("/rooms"), None, _) => {
+            let rooms = db::Room::get_summaries(db)?;
+            for room in &rooms {
+                let _ = writeln!(
+                    out,
+                    "{} admin={} members={}{}{}",
+                    room.name,
+                    room.admin_name,
+                    room.members,
+                    if room.private { " private" } else { "" },
+                    if room.persistent { " persistent" } else { "" },
+                );
+            }
+            let _ = writeln!(out, "({} rooms)", rooms.len());
+        }
+        (Some("/kick"), Some(user_name), None) => {
+            let kicked = db::transaction(db, || {
+                let kicked = db::User::kick(db, user_name)?;
+                if kicked {
+                    audit(db, "/kick", None, Some(user_name), "")?;
+                }
+                Ok(kicked)
+            })?;
+            if kicked {
+                let _ = writeln!(out, "kicked {}", user_name);
+            } else {
+                let _ = writeln!(out, "user not found");
+            }
+        }
+        (Some("/ban"), Some(user_name), Some(room_name)) => {
+            let Some(room) = db::Room::get(db, room_name)? else {
+                let _ = writeln!(out, "room not found");
+                return Ok(());
+            };
+            let Some(user_id) = db::User::get_id(db, user_name)? else {
+                let _ = writeln!(out, "user not found");
+                return Ok(());
+            };
+            if room.is_admin(user_id) {
+                let _ = writeln!(out, "cannot ban the room admin, /close the room instead");
+                return Ok(());
+            }
+            db::transaction(db, || {
+                rooms::ban_user(db, &room, room_name, user_id, user_name, SERVER_USER_ID)?;
+                audit(db, "/ban", Some((&room, room_name)), Some(user_name), "")
+            })?;
+            let _ = writeln!(out, "banned {} from {}", user_name, room_name);
+        }
+        (Some("/close"), Some(room_name), None) => {
+            let Some(room) = db::Room::get(db, room_name)? else {
+                let _ = writeln!(out, "room not found");
+                return Ok(());
+            };
+            db::transaction(db, || {
+                audit(db, "/close", Some((&room, room_name)), None, "")?;
+                rooms::close_room(db, &room, room_name, SERVER_USER_ID)
+            })?;
+            let _ = writeln!(out, "closed {}", room_name);
+        }
+        (Some("/persist"), Some(room_name), Some(mode @ ("on" | "off"))) => {
+            let Some(room) = db::Room::get(db, room_name)? else {
+                let _ = writeln!(out, "room not found");
+                return Ok(());
+            };
+            let persistent = mode == "on";
+            if !persistent && room.is_admin(SERVER_USER_ID) {
+                // ninguém mais fecharia a sala ao desconectar
+                let _ = writeln!(out, "{} has no admin left, /close it instead", room_name);
+                return Ok(());
+            }
+            let flag = if persistent { "SIM" } else { "NAO" };
+            let msg = format!("SALA_ALTERADA {} PERSISTENTE {}\n", room_name, flag);
+            db::transaction(db, || {
+                room.set_persistent(db, persistent)?;
+                audit(db, "/persist", Some((&room, room_name)), None, mode)?;
+                room.broadcast(db, &msg, SERVER_USER_ID, 0)
+            })?;
+            let _ = writeln!(out, "{} persistent: {}", room_name, mode);
+        }
+        (Some("/say"), Some(room_names), Some(_)) => {
+            let text = remainder(line, 2);
+            for room_name in room_names.split(',') {
+                let Some(room) = db::Room::get(db, room_name)? else {
+                    let _ = writeln!(out, "room {} not found", room_name);
+                    continue;
+                };
+                let msg_id = db::transaction(db, || {
+                    let msg_id = room.post(db, "server", text)?;
+                    let msg = format!(
+                        "ID_MENSAGEM {} {}\nMENSAGEM {} server {}\n",
+                        room_name, msg_id, room_name, text
+                    );
+                    room.broadcast(db, &msg, SERVER_USER_ID, 0)?;
+                    audit(db, "/say", Some((&room, room_name)), None, text)?;
+                    Ok(msg_id)
+                })?;
+                let _ = writeln!(out, "sent {} to {}", msg_id, room_name);
+            }
+        }
+        (Some("/announce"), Some(_), _) => {
+            let text = remainder(line, 1);
+            db::User::send_to_all(db, &format!("ANUNCIO {}\n", text))?;
+            audit(db, "/announce", None, None, text)?;
+            let _ = writeln!(out, "announced");
+        }
+        (Some("/motd"), _, _) => {
+            let motd = remainder(line, 1);
+            audit(db, "/motd", None, None, motd)?;
+            if motd.is_empty() {
+                db::set_setting(db, "motd", None)?;
+                let _ = writeln!(out, "motd cleared");
+            } else {
+                db::set_setting(db, "motd", Some(motd))?;
+                let _ = writeln!(out, "motd set");
+            }
+        }
+        (Some("/maintenance"), Some("cancel"), None) => {
+            if db::get_setting(db, "maintenance_at")?.is_none() {
+                let _ = writeln!(out, "no maintenance scheduled");
+                return Ok(());
+            }
+            db::set_setting(db, "maintenance_at", None)?;
+            db::set_setting(db, "maintenance_msg", None)?;
+            db::User::send_to_all(db, "MANUTENCAO_CANCELADA\n")?;
+            audit(db, "/maintenance", None, None, "cancel")?;
+            let _ = writeln!(out, "maintenance cancelled");
+        }
+        (Some("/maintenance"), Some(minutes), Some(_)) => {
+            let Ok(minutes @ 1..) = minutes.parse::<i64>() else {
+                let _ = writeln!(out, "invalid minutes");
+                return Ok(());
+            };
+            let text = remainder(line, 2).to_string();
+            let at = db::now_millis() + minutes * 60_000;
+            db::set_setting(db, "maintenance_at", Some(&at.to_string()))?;
+            db::set_setting(db, "maintenance_msg", Some(&text))?;
+            db::User::send_to_all(db, &maintenance_notice(at, &text))?;
+            audit(db, "/maintenance", None, None, remainder(line, 1))?;
+            task::spawn(maintenance_reminders(db, at, text));
+            let _ = writeln!(out, "maintenance scheduled in {} minutes", minutes);
+        }
+        (Some("/gban"), Some(kind @ ("user" | "ip")), Some(target)) => {
+            let mut words = line.split_whitespace().skip(3);
+            let expires = match words.next().map(parse_duration) {
+                Some(Some(duration)) => duration.map(|ms| db::now_millis() + ms),
+                _ => {
+                    let _ = writeln!(out, "invalid duration, use e.g. 30m, 12h, 7d or perm");
+                    return Ok(());
+                }
+            };
+            let reason = remainder(line, 4);
+            let reason = if reason.is_empty() {
+                "sem motivo"
+            } else {
+                reason
+            };
+            let kind = if kind == "user" {
+                BanKind::User
+            } else {
+                BanKind::Ip
+            };
+            let kicked = match kind {
+                BanKind::User => vec![target.to_string()],
+                BanKind::Ip => {
+                    let Some(cidr) = Cidr::parse(target) else {
+                        let _ = writeln!(out, "invalid address or CIDR range");
+                        return Ok(());
+                    };
+                    db::User::get_all_addrs(db)?
+                        .into_iter()
+                        .filter(|(_, addr)| addr.parse().is_ok_and(|ip| cidr.contains(ip)))
+                        .map(|(name, _)| name)
+                        .collect()
+                }
+            };
+            let id = db::GlobalBan::create(db, kind, target, reason, expires)?;
+            audit(db, "/gban", None, Some(target), remainder(line, 1))?;
+            let _ = writeln!(out, "ban {} added", id);
+            for name in kicked {
+                if db::User::kick(db, &name)? {
+                    let _ = writeln!(out, "kicked {}", name);
+                }
+            }
+        }
+        (Some("/gbans"), None, _) => {
+            let bans = db::GlobalBan::get_active(db, None)?;
+            for ban in &bans {
+                let expires = match ban.expires {
+                    Some(expires) => format!("{}s", (expires - db::now_millis()) / 1000),
+                    None => "perm".to_string(),
+                };
+                let _ = writeln!(
+                    out,
+                    "{} {} {} expires={} reason={:?}",
+                    ban.id,
+                    ban.kind.as_str(),
+                    ban.target,
+                    expires,
+                    ban.reason
+                );
+            }
+            let _ = writeln!(out, "({} bans)", bans.len());
+        }
+        (Some("/gunban"), Some(id), None) => match id.parse() {
+            Ok(id) if db::GlobalBan::delete(db, id)? => {
+                audit(db, "/gunban", None, None, &id.to_string())?;
+                let _ = writeln!(out, "ban {} lifted", id);
+            }
+            _ => {
+                let _ = writeln!(out, "ban not found");
+            }
+        },
+        (Some("/audit"), room_name, None) => {
+            let entries = db::AuditEntry::get_recent(db, None, room_name, AUDIT_PAGE)?;
+            let now = db::now_millis();
+            for entry in &entries {
+                let _ = writeln!(
+                    out,
+                    "{} {}s ago {}@{} {} room={} target={} {:?}",
+                    entry.id,
+                    (now - entry.at) / 1000,
+                    entry.actor,
+                    entry.addr,
+                    entry.action,
+                    entry.room_name.as_deref().unwrap_or("-"),
+                    entry.target.as_deref().unwrap_or("-"),
+                    entry.detail
+                );
+            }
+            let _ = writeln!(out, "({} entries)", entries.len());
+        }
+        (Some("/log"), None, _) => {
+            let _ = writeln!(out, "{}", log::describe());
+        }
+        (Some("/log"), Some("format"), Some(format @ ("json" | "text"))) => {
+            log::set_json(format == "json");
+            let _ = writeln!(out, "{}", log::describe());
+        }
+        (Some("/log"), Some("payloads"), Some(mode @ ("on" | "off"))) => {
+            log::set_payloads(mode == "on");
+            if mode == "on" {
+                warn!(Admin, "message contents are now logged");
+            }
+            let _ = writeln!(out, "{}", log::describe());
+        }
+        (Some("/log"), Some(spec), None) => match log::configure(spec) {
+            Ok(()) => {
+                let _ = writeln!(out, "{}", log::describe());
+            }
+            Err(err) => {
+                let _ = writeln!(out, "{}", err);
+            }
+        },
+        (Some("/sql"), Some(_), _) => {
+            let query = line.strip_prefix("/sql").unwrap_or("").trim();
+            sql.query(db, query, out);
+        }
+        (Some("/sql-write"), Some(mode @ ("on" | "off")), None) => {
+            sql.set_writes(mode == "on", out);
+        }
+        (Some("/commit"), None, _) => {
+            if let Some(query) = sql.commit(db, out) {
+                audit(db, "/commit", None, None, &query)?;
+            }
+        }
+        (Some("/rollback"), None, _) => sql.rollback(out),
+        (Some("/help"), None, _) => out.push_str(HELP),
+        _ => {
+            let _ = writeln!(out, "unknown command, try /help");
+        }
+    }
+    Ok(())
+}
+
+fn audit(
+    db: &Db,
+    action: &str,
+    room: Option<(&db::Room, &str)>,
+    target: Option<&str>,
+    detail: &str,
+) -> db::DbResult<()> {
+    db::AuditEntry::record(db, &db::Actor::CONSOLE, action, room, target, detail)
+}
+
+/// O texto depois das `words` primeiras palavras da linha.
+fn remainder(line: &str, words: usize) -> &str {
+    let mut split = line.split_whitespace();
+    for _ in 0..words {
+        split.next();
+    }
+    split.remainder().unwrap_or("").trim()
+}
+
+/// `30s`, `15m`, `12h`, `7d` em ms, ou `perm` para `Some(None)`.
+fn parse_duration(text: &str) -> Option<Option<i64>> {
+    if text == "perm" {
+        return Some(None);
+    }
+    let unit = match text.chars().last()? {
+        's' => 1_000,
+        'm' => 60_000,
+        'h' => 3_600_000,
+        'd' => 86_400_000,
+        _ => return None,
+    };
+    let amount = text[..text.len() - 1].parse::<i64>().ok()?;
+    Some(Some(amount.checked_mul(unit)?))
+}
+
+/// `MANUTENCAO <minutos restantes> <texto>`
+pub fn maintenance_notice(at: i64, text: &str) -> String {
+    let minutes = ((at - db::now_millis()).max(0) + 59_999) / 60_000;
+    format!("MANUTENCAO {} {}\n", minutes, text)
+}
+
+/// Repete o aviso de manutenção nos `MAINTENANCE_REMINDERS`, até ser cancelada ou reagendada.
+async fn maintenance_reminders(db: &'static Db, at: i64, text: String) {
+    for minutes in MAINTENANCE_REMINDERS {
+        let remind_at = at - minutes * 60_000;
+        let wait = remind_at - db::now_millis();
+        if wait <= 0 {
+            continue;
+        }
+        task::sleep(std::time::Duration::from_millis(wait as u64)).await;
+        match db::get_setting(db, "maintenance_at") {
+            Ok(Some(scheduled)) if scheduled == at.to_string() => {}
+            Ok(_) => return,
+            Err(err) => {
+                error!(Admin, "maintenance reminder failed: {}", err);
+                return;
+            }
+        }
+        if let Err(err) = db::User::send_to_all(db, &maintenance_notice(at, &text)) {
+            error!(Admin, "maintenance reminder failed: {}", err);
+        }
+    }
+    let cleared = db::set_setting(db, "maintenance_at", None)
+        .and_then(|()| db::set_setting(db, "maintenance_msg", None));
+    if let Err(err) = cleared {
+        error!(Admin, "cannot clear maintenance: {}", err);
+    }
+}
@@ -0,0 +1,37 @@
+//! Cliente do console de administração do servidor.
+//!
+//! `chat-admin [socket]`, o socket padrão é `$CHAT_ADMIN_SOCKET` ou `chat-admin.sock`. O
+//! servidor só abre o socket se `CHAT_ADMIN_SOCKET` estiver definido.
+//! Se `$CHAT_ADMIN_SECRET` estiver definido, faz `/login` automaticamente.
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+
+fn main() {
+    let path = std::env::args()
+        .nth(1)
+        .or_else(|| std::env::var("CHAT_ADMIN_SOCKET").ok())
+        .unwrap_or_else(|| "chat-admin.sock".to_string());
+    let mut stream = UnixStream::connect(&path).unwrap_or_else(|err| {
+        eprintln!("cannot connect to {}: {}", path, err);
+        std::process::exit(1);
+    });
+
+    let mut reader = stream.try_clone().unwrap();
+    let output = std::thread::spawn(move || {
+        let mut stdout = io::stdout();
+        let mut buf = [0; 4096];
+        while let Ok(n @ 1..) = reader.read(&mut buf) {
+            if stdout.write_all(&buf[..n]).is_err() || stdout.flush().is_err() {
+                break;
+            }
+        }
+    });
+
+    if let Ok(secret) = std::env::var("CHAT_ADMIN_SECRET") {
+        let _ = writeln!(stream, "/login {}", secret);
+    }
+    let _ = io::copy(&mut io::stdin(), &mut stream);
+    let _ = stream.shutdown(Shutdown::Write);
+    let _ = output.join();
+}
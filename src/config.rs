@@ -0,0 +1,159 @@
+use core::time::Duration;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::cidr::Cidr;
+
+/// Políticas do servidor, lidas de variáveis de ambiente `CHAT_*` na inicialização.
+pub struct Config {
+    /// toda sala criada sobrevive à desconexão do seu admin
+    pub persistent_rooms: bool,
+    /// salas persistentes sem atividade por esse tempo são fechadas, padrão 7 dias,
+    /// `None` = nunca
+    pub room_expiry: Option<Duration>,
+    /// mensagens guardadas no histórico de cada sala, `None` = sem limite
+    pub room_history: Option<u32>,
+    /// comandos que uma conexão pode mandar de uma vez
+    pub rate_burst: u32,
+    /// comandos por segundo recuperados pelo token bucket
+    pub rate_per_sec: f64,
+    /// violações do limite em 10s antes de desconectar
+    pub rate_max_strikes: u32,
+    /// socket Unix do console de administração, `None` = desligado
+    pub admin_socket: Option<PathBuf>,
+    /// permissões do arquivo do socket, quem pode abri-lo pode administrar
+    pub admin_socket_mode: u32,
+    /// se definido, sessões no socket precisam de `/login <segredo>`
+    pub admin_secret: Option<String>,
+    /// mensagem do dia inicial, de `CHAT_MOTD` ou do arquivo `CHAT_MOTD_FILE`
+    pub motd: Option<String>,
+    /// endereço HTTP de `/metrics`, `None` = desligado
+    pub metrics_addr: Option<SocketAddr>,
+    /// tempo máximo esperando os clientes saírem ao receber SIGINT/SIGTERM
+    pub shutdown_deadline: Duration,
+    /// arquivo SQLite onde o banco é exportado ao encerrar (não é recarregado), `None` = descartado
+    pub dump_file: Option<PathBuf>,
+    /// níveis de log iniciais, ex.: `info,db=debug` (veja `log::configure`)
+    pub log: String,
+    /// log em JSON, uma linha por evento, em vez de texto
+    pub log_json: bool,
+    /// conteúdo das mensagens no log, só para depuração
+    pub log_payloads: bool,
+    /// tempo para concluir `REGISTRO`/`AUTENTICACAO`/`CHAVE_SIMETRICA`, a conexão ocupa uma
+    /// vaga dos limites de conexão enquanto isso
+    pub handshake_timeout: Duration,
+    /// silêncio do cliente antes de o servidor mandar `PING`, `None` = nunca
+    pub ping_interval: Option<Duration>,
+    /// silêncio do cliente antes de desconectá-lo, `None` = nunca; vale também para quem não
+    /// responde `PING`, qualquer linha recebida conta
+    pub idle_timeout: Option<Duration>,
+    /// conexões simultâneas no servidor, `None` = sem limite
+    pub max_connections: Option<u32>,
+    /// conexões simultâneas de um mesmo IP, `None` = sem limite
+    pub max_connections_per_ip: Option<u32>,
+    /// conexões novas que um IP pode abrir de uma vez
+    pub connect_burst_per_ip: u32,
+    /// conexões novas por segundo recuperadas por IP
+    pub connect_rate_per_ip: f64,
+    /// endereços e faixas fora de todos os limites de conexão, ex.: o gerador de carga
+    pub conn_allowlist: Vec<Cidr>,
+    /// diretório com catálogos de tradução `<idioma>.txt` (veja `i18n.rs`)
+    pub lang_dir: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self {
+            persistent_rooms: env_parse("CHAT_PERSISTENT_ROOMS").unwrap_or(false),
+            room_expiry: Some(env_parse("CHAT_ROOM_EXPIRY_SECS").unwrap_or(7 * 24 * 3600))
+                .filter(|&secs| secs != 0)
+                .map(Duration::from_secs),
+            room_history: Some(env_parse("CHAT_ROOM_HISTORY").unwrap_or(1000))
+                .filter(|&max| max != 0),
+            rate_burst: env_parse("CHAT_RATE_BURST").unwrap_or(20),
+            rate_per_sec: env_parse("CHAT_RATE_PER_SEC")
+                .filter(|&rate: &f64| rate > 0.0)
+                .unwrap_or(5.0),
+            rate_max_strikes: env_parse("CHAT_RATE_MAX_STRIKES").unwrap_or(20),
+            admin_socket: std::env::var("CHAT_ADMIN_SOCKET")
+                .ok()
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from),
+            admin_socket_mode: std::env::var("CHAT_ADMIN_SOCKET_MODE")
+                .ok()
+                .and_then(|mode| u32::from_str_radix(&mode, 8).ok())
+                .unwrap_or(0o600),
+            admin_secret: std::env::var("CHAT_ADMIN_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty()),
+            motd: std::env::var("CHAT_MOTD_FILE")
+                .ok()
+                .and_then(|path| match std::fs::read_to_string(&path) {
+                    Ok(motd) => Some(motd),
+                    Err(err) => {
+                        warn!(Server, "cannot read motd file {:?}: {}", path, err);
+                        None
+                    }
+                })
+                .or_else(|| std::env::var("CHAT_MOTD").ok())
+                .filter(|motd| !motd.trim().is_empty()),
+            metrics_addr: env_parse("CHAT_METRICS_ADDR"),
+            shutdown_deadline: Duration::from_secs(env_parse("CHAT_SHUTDOWN_SECS").unwrap_or(10)),
+            dump_file: std::env::var("CHAT_DUMP_FILE")
+                .ok()
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from),
+            log: std::env::var("CHAT_LOG").unwrap_or_else(|_| "info".to_string()),
+            log_json: std::env::var("CHAT_LOG_FORMAT").is_ok_and(|format| format == "json"),
+            log_payloads: env_parse("CHAT_LOG_PAYLOADS").unwrap_or(false),
+            handshake_timeout: Duration::from_secs(
+                env_parse("CHAT_HANDSHAKE_TIMEOUT_SECS")
+                    .filter(|&secs| secs != 0)
+                    .unwrap_or(10),
+            ),
+            ping_interval: Some(env_parse("CHAT_PING_SECS").unwrap_or(30))
+                .filter(|&secs| secs != 0)
+                .map(Duration::from_secs),
+            idle_timeout: Some(env_parse("CHAT_IDLE_TIMEOUT_SECS").unwrap_or(120))
+                .filter(|&secs| secs != 0)
+                .map(Duration::from_secs),
+            max_connections: Some(env_parse("CHAT_MAX_CONNECTIONS").unwrap_or(1000))
+                .filter(|&max| max != 0),
+            max_connections_per_ip: Some(env_parse("CHAT_MAX_CONNECTIONS_PER_IP").unwrap_or(20))
+                .filter(|&max| max != 0),
+            connect_burst_per_ip: env_parse("CHAT_CONNECT_BURST_PER_IP").unwrap_or(10),
+            connect_rate_per_ip: env_parse("CHAT_CONNECT_RATE_PER_IP")
+                .filter(|&rate: &f64| rate > 0.0)
+                .unwrap_or(2.0),
+            conn_allowlist: std::env::var("CHAT_CONN_ALLOWLIST")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .filter_map(|item| {
+                    let cidr = Cidr::parse(item);
+                    if cidr.is_none() {
+                        warn!(
+                            Server,
+                            "ignoring invalid CHAT_CONN_ALLOWLIST entry {:?}", item
+                        );
+                    }
+                    cidr
+                })
+                .collect(),
+            lang_dir: std::env::var("CHAT_LANG_DIR")
+                .ok()
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from),
+        }
+    }
+}
+
+fn env_parse<T: std::str::FromStr>(var: &str) -> Option<T> {
+    let value = std::env::var(var).ok()?;
+    let parsed = value.parse().ok();
+    if parsed.is_none() {
+        warn!(Server, "ignoring invalid {}={:?}", var, value);
+    }
+    parsed
+}
@@ -1,25 +1,58 @@
 PRAGMA foreign_keys = ON;
 
+-- configurações alteráveis em tempo de execução (motd, manutenção agendada)
+CREATE TABLE settings(
+    key     TEXT    PRIMARY KEY,
+    value   TEXT    NOT NULL
+);
+
 CREATE TABLE users(
     id          INTEGER PRIMARY KEY CHECK(id != 0),
-    name        TEXT    NOT NULL
+    name        TEXT    NOT NULL,
+    kicked      BOOL    NOT NULL DEFAULT FALSE, -- a conexão deve ser encerrada
+    addr        TEXT    NOT NULL DEFAULT '' -- IP de origem
 );
 
 CREATE UNIQUE INDEX user_names ON users(name);
 
-CREATE TABLE rooms(
+CREATE TABLE global_bans(
     id      INTEGER PRIMARY KEY CHECK(id != 0),
+    kind    TEXT    NOT NULL CHECK(kind IN ('user', 'ip')),
+    target  TEXT    NOT NULL, -- nome de usuário ou IP/CIDR
+    reason  TEXT    NOT NULL,
+    expires INTEGER -- ms, NULL = permanente
+);
+
+-- AUTOINCREMENT: o id de uma sala fechada não é reaproveitado, audit_log depende disso
+CREATE TABLE rooms(
+    id      INTEGER PRIMARY KEY AUTOINCREMENT CHECK(id != 0),
     name    TEXT    NOT NULL,
     private BOOL    NOT NULL,
-    pass    TEXT    NOT NULL,
-    admin   INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE
+    pass    TEXT    NOT NULL, -- pbkdf2_sha256$iters$salt$hash, '' = sem senha
+    admin   INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    topic   TEXT    NOT NULL DEFAULT '',
+    max_members INTEGER NOT NULL DEFAULT 0 CHECK(max_members >= 0), -- 0 = sem limite
+    persistent  BOOL    NOT NULL DEFAULT FALSE,
+    slow_mode   INTEGER NOT NULL DEFAULT 0 CHECK(slow_mode >= 0), -- segundos, 0 = desligado
+    last_activity INTEGER NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER))
 );
 
 CREATE UNIQUE INDEX room_names ON rooms(name);
 
+-- salas persistentes passam ao usuário server (id 1) em vez de cascatear, e ficam com ele:
+-- nomes não têm senha, quem registrasse o nome do criador tomaria a sala. Daí em diante só o
+-- console as fecha, ou a expiração por inatividade
+CREATE TRIGGER persistent_room_orphaned
+    BEFORE DELETE ON users
+BEGIN
+    UPDATE rooms SET admin = 1
+        WHERE admin = OLD.id AND persistent;
+END;
+
 CREATE TABLE rel_room_user(
     room_id INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
     user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    last_post INTEGER NOT NULL DEFAULT 0, -- ms, para o modo lento
     UNIQUE(room_id, user_id)
 ); 
 
@@ -41,6 +74,71 @@ CREATE TABLE rel_room_banned(
     UNIQUE(room_id, user_id)
 );
 
+CREATE TABLE rel_room_queue(
+    id      INTEGER PRIMARY KEY CHECK(id != 0),
+    room_id INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    UNIQUE(room_id, user_id)
+);
+
+CREATE TRIGGER member_leaves_queue
+    AFTER INSERT ON rel_room_user
+BEGIN
+    DELETE FROM rel_room_queue
+        WHERE room_id = NEW.room_id AND user_id = NEW.user_id;
+END;
+
+CREATE TRIGGER banned_leaves_queue
+    AFTER INSERT ON rel_room_banned
+BEGIN
+    DELETE FROM rel_room_queue
+        WHERE room_id = NEW.room_id AND user_id = NEW.user_id;
+END;
+
+-- histórico das mensagens de sala, `messages` guarda só o texto a ser entregue
+CREATE TABLE room_msgs(
+    id          INTEGER PRIMARY KEY CHECK(id != 0),
+    room_id     INTEGER NOT NULL REFERENCES rooms(id)     ON DELETE CASCADE,
+    user_name   TEXT    NOT NULL, -- usuários somem ao desconectar
+    parent_id   INTEGER REFERENCES room_msgs(id) ON DELETE CASCADE, -- sempre a raiz da thread
+    reply_count INTEGER NOT NULL DEFAULT 0,
+    msg         TEXT    NOT NULL,
+    CHECK(LENGTH(msg) != 0)
+);
+
+CREATE INDEX room_msgs_threads ON room_msgs(parent_id);
+CREATE INDEX room_msgs_rooms ON room_msgs(room_id);
+
+CREATE TRIGGER reply_counted
+    AFTER INSERT ON room_msgs
+    WHEN NEW.parent_id IS NOT NULL
+BEGIN
+    UPDATE room_msgs SET reply_count = reply_count + 1
+        WHERE id = NEW.parent_id;
+END;
+
+CREATE TABLE room_pins(
+    room_id INTEGER NOT NULL REFERENCES rooms(id)     ON DELETE CASCADE,
+    msg_id  INTEGER NOT NULL REFERENCES room_msgs(id) ON DELETE CASCADE,
+    UNIQUE(room_id, msg_id)
+);
+
+-- menções não lidas de um membro, somem quando ele sai da sala ou desconecta
+CREATE TABLE mentions(
+    id      INTEGER PRIMARY KEY CHECK(id != 0),
+    user_id INTEGER NOT NULL REFERENCES users(id)     ON DELETE CASCADE,
+    room_id INTEGER NOT NULL REFERENCES rooms(id)     ON DELETE CASCADE,
+    msg_id  INTEGER NOT NULL REFERENCES room_msgs(id) ON DELETE CASCADE,
+    UNIQUE(user_id, msg_id)
+);
+
+CREATE TRIGGER member_leaves_mentions
+    AFTER DELETE ON rel_room_user
+BEGIN
+    DELETE FROM mentions
+        WHERE room_id = OLD.room_id AND user_id = OLD.user_id;
+END;
+
 CREATE TABLE messages(
     id      INTEGER PRIMARY KEY CHECK(id != 0),
     msg     TEXT    NOT NULL,
@@ -54,9 +152,42 @@ CREATE TABLE rel_user_msg(
     UNIQUE(user_id, msg_id)
 );
 
+CREATE INDEX rel_user_msg_msgs ON rel_user_msg(msg_id);
+
+-- entregue a todos, o texto não serve mais
+CREATE TRIGGER message_delivered
+    AFTER DELETE ON rel_user_msg
+    WHEN NOT EXISTS (SELECT 1 FROM rel_user_msg WHERE msg_id = OLD.msg_id)
+BEGIN
+    DELETE FROM messages WHERE id = OLD.msg_id;
+END;
+
 CREATE VIEW view_user_msgs AS
     SELECT rel.id, rel.user_id, msg.msg FROM rel_user_msg rel
     INNER JOIN messages msg ON msg.id = rel.msg_id;
     -- rel_id, user_id, msg
 
+-- ações de moderação e administração, só aceita INSERT
+CREATE TABLE audit_log(
+    id        INTEGER PRIMARY KEY CHECK(id != 0),
+    at        INTEGER NOT NULL, -- ms
+    actor     TEXT    NOT NULL, -- nome do usuário, 'console' ou 'server'
+    addr      TEXT    NOT NULL, -- IP de origem, 'local' para o console
+    action    TEXT    NOT NULL,
+    room_id   INTEGER, -- sem REFERENCES, o registro sobrevive à sala
+    room_name TEXT,
+    target    TEXT,
+    detail    TEXT    NOT NULL DEFAULT ''
+);
+
+CREATE INDEX audit_log_rooms ON audit_log(room_id);
+
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
 
+CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
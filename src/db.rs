@@ -1,22 +1,32 @@
-use sqlite::{ConnectionThreadSafe as Db, State};
+use async_std::task;
+use openssl::{base64, hash::MessageDigest, memcmp, pkcs5, rand::rand_bytes};
+use sqlite::{BindableWithIndex, ConnectionThreadSafe as Db, ParameterIndex, State, Statement};
+use std::cell::{Cell, RefCell};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard, PoisonError};
 
-const SQL_LOG_ENABLE: bool = true;
+use crate::cidr::Cidr;
+use crate::metrics::TimedStatement;
+
+/// Usuário `server` criado por `populate.sql`, dono de `geral`.
+pub const SERVER_USER_ID: i64 = 1;
+
+const PASS_HASH_SCHEME: &str = "pbkdf2_sha256";
+const PASS_HASH_ITERS: usize = 100_000;
+/// Iterações aceitas num hash guardado, fora disso ele é tido como corrompido.
+const PASS_HASH_ITERS_ACCEPTED: std::ops::RangeInclusive<usize> = 10_000..=1_000_000;
+const PASS_SALT_LEN: usize = 16;
+const PASS_HASH_LEN: usize = 32;
 
 macro_rules! sqlite {
     ($db:expr, $sql:expr, $($arg:expr),* $(,)?) => {{
-        if SQL_LOG_ENABLE {
-            eprint!("(QUERY)\t");
-            let mut _split = $sql.split_whitespace();
-            if let Some(_s) = _split.next() {
-                eprint!("{}", _s);
-                for _s in _split {
-                    eprint!(" {}", _s);
-                }
-            }
+        if crate::log::enabled(crate::log::Target::Db, crate::log::Level::Debug) {
+            let mut _line = $sql.split_whitespace().collect::<Vec<_>>().join(" ");
             $({
-                eprint!(", {:?}", $arg);
+                _line.push_str(&format!(", {:?}", $arg));
             })*
-            eprintln!();
+            debug!(Db, "{}", _line);
         }
         sqlite_no_log!($db, $sql, $($arg),*)
     }};
@@ -24,16 +34,556 @@ macro_rules! sqlite {
 
 macro_rules! sqlite_no_log {
     ($db:expr, $sql:expr, $($arg:expr),* $(,)?) => {{
-        let mut _query = $db.prepare($sql).unwrap();
+        let _lock = crate::db::lock();
+        let _start = std::time::Instant::now();
+        let mut _query = $db.prepare($sql)?;
         let mut _i = 1;
         $({
-            _query.bind((_i, $arg)).unwrap();
+            _query.bind((_i, $arg))?;
             _i += 1;
         })*
-        _query
+        crate::db::Query {
+            statement: crate::metrics::TimedStatement::new(_query, _start),
+            _lock,
+        }
     }};
 }
 
+/// Falha de uma operação no banco.
+#[derive(Debug)]
+pub enum DbError {
+    /// restrição violada (`CHECK`, `UNIQUE`, `FOREIGN KEY`...), culpa da entrada do cliente
+    Constraint(String),
+    /// banco ocupado ou travado, vale tentar de novo
+    Busy,
+    /// qualquer outra coisa, o estado da conexão não é confiável
+    Other(sqlite::Error),
+}
+
+pub type DbResult<T> = Result<T, DbError>;
+
+impl From<sqlite::Error> for DbError {
+    fn from(err: sqlite::Error) -> Self {
+        // códigos primários do SQLite, sem os bits estendidos
+        match err.code.map(|code| code & 0xff) {
+            Some(19) => DbError::Constraint(err.message.unwrap_or_default()),
+            Some(5) | Some(6) => DbError::Busy,
+            _ => DbError::Other(err),
+        }
+    }
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::Constraint(message) => write!(f, "constraint failed: {}", message),
+            DbError::Busy => f.write_str("database busy"),
+            DbError::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Trava do banco. A conexão é uma só para todas as tarefas, então uma transação aberta
+/// por uma veria no meio dela as instruções das outras, e um `ROLLBACK` as desfaria junto.
+/// Toda instrução segura a trava enquanto existe e `transaction` a segura do começo ao fim.
+static LOCK: Mutex<()> = Mutex::new(());
+
+thread_local! {
+    static HELD: RefCell<Option<MutexGuard<'static, ()>>> = const { RefCell::new(None) };
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Posse da trava do banco, reentrante na mesma thread. Não é `Send`: não pode atravessar um
+/// `.await`, senão a tarefa seguraria o banco inteiro enquanto espera a rede.
+pub struct Lock(PhantomData<*const ()>);
+
+pub fn lock() -> Lock {
+    DEPTH.with(|depth| {
+        if depth.get() == 0 {
+            let guard = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+            HELD.with(|held| *held.borrow_mut() = Some(guard));
+        }
+        depth.set(depth.get() + 1);
+    });
+    Lock(PhantomData)
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        DEPTH.with(|depth| {
+            depth.set(depth.get() - 1);
+            if depth.get() == 0 {
+                HELD.with(|held| held.borrow_mut().take());
+            }
+        });
+    }
+}
+
+/// Instrução preparada por `sqlite!`, segurando a trava do banco até ser descartada.
+pub struct Query<'l> {
+    // descartada antes da trava
+    pub statement: TimedStatement<'l>,
+    pub _lock: Lock,
+}
+
+impl<'l> Deref for Query<'l> {
+    type Target = TimedStatement<'l>;
+
+    fn deref(&self) -> &TimedStatement<'l> {
+        &self.statement
+    }
+}
+
+impl DerefMut for Query<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.statement
+    }
+}
+
+/// Roda `f` numa transação: ou tudo que ela escreveu fica, ou nada fica. Falha de `f`, do
+/// `RELEASE` ou pânico no meio desfazem tudo. Transações aninhadas viram savepoints internos,
+/// desfeitos sem derrubar a de fora.
+pub fn transaction<T>(db: &Db, f: impl FnOnce() -> DbResult<T>) -> DbResult<T> {
+    let _lock = lock();
+    db.execute("SAVEPOINT tx")?;
+    let mut savepoint = Savepoint {
+        db,
+        released: false,
+    };
+    let result = f();
+    if result.is_ok() {
+        db.execute("RELEASE tx")?;
+        savepoint.released = true;
+    }
+    result
+}
+
+struct Savepoint<'a> {
+    db: &'a Db,
+    released: bool,
+}
+
+impl Drop for Savepoint<'_> {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        debug!(Db, "transaction rolled back");
+        if let Err(err) = self.db.execute("ROLLBACK TO tx; RELEASE tx") {
+            error!(Db, "cannot roll back transaction: {}", err);
+        }
+    }
+}
+
+/// Parâmetro que é enviado ao banco normalmente, mas aparece como `<redacted>` no log.
+pub struct Secret<'a>(pub &'a str);
+
+impl std::fmt::Debug for Secret<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl BindableWithIndex for Secret<'_> {
+    fn bind<T: ParameterIndex>(self, statement: &mut Statement, index: T) -> sqlite::Result<()> {
+        self.0.bind(statement, index)
+    }
+}
+
+/// Texto livre (mensagens, tópicos, detalhes da auditoria), que aparece no log só como
+/// `<N bytes>` a não ser com `log::payloads()`.
+pub struct Payload<'a>(pub &'a str);
+
+impl std::fmt::Debug for Payload<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if crate::log::payloads() {
+            write!(f, "{:?}", self.0)
+        } else {
+            write!(f, "<{} bytes>", self.0.len())
+        }
+    }
+}
+
+impl BindableWithIndex for Payload<'_> {
+    fn bind<T: ParameterIndex>(self, statement: &mut Statement, index: T) -> sqlite::Result<()> {
+        self.0.bind(statement, index)
+    }
+}
+
+/// Senha de sala já com hash, pronta para o banco.
+pub struct PassHash(String);
+
+impl PassHash {
+    /// O pbkdf2 leva dezenas de ms, roda fora do executor para não parar as outras tarefas.
+    pub async fn new(pass: &str) -> Self {
+        let pass = pass.to_string();
+        PassHash(task::spawn_blocking(move || hash_pass(&pass)).await)
+    }
+}
+
+/// `verify_pass` fora do executor, como `PassHash::new`.
+async fn verify_pass_blocking(stored: &str, pass: &str) -> bool {
+    let (stored, pass) = (stored.to_string(), pass.to_string());
+    task::spawn_blocking(move || verify_pass(&stored, &pass)).await
+}
+
+/// Gera `pbkdf2_sha256$iters$salt$hash`. Senha vazia continua vazia (sala sem senha).
+fn hash_pass(pass: &str) -> String {
+    if pass.is_empty() {
+        return String::new();
+    }
+    let mut salt = [0; PASS_SALT_LEN];
+    rand_bytes(&mut salt).unwrap();
+    let mut hash = [0; PASS_HASH_LEN];
+    pkcs5::pbkdf2_hmac(
+        pass.as_bytes(),
+        &salt,
+        PASS_HASH_ITERS,
+        MessageDigest::sha256(),
+        &mut hash,
+    )
+    .unwrap();
+    format!(
+        "{}${}${}${}",
+        PASS_HASH_SCHEME,
+        PASS_HASH_ITERS,
+        base64::encode_block(&salt),
+        base64::encode_block(&hash),
+    )
+}
+
+fn verify_pass(stored: &str, pass: &str) -> bool {
+    if stored.is_empty() {
+        return pass.is_empty();
+    }
+    let mut fields = stored.split('$');
+    let (Some(PASS_HASH_SCHEME), Some(iters), Some(salt), Some(expected), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return false;
+    };
+    let (Ok(iters), Ok(salt), Ok(expected)) = (
+        iters.parse::<usize>(),
+        base64::decode_block(salt),
+        base64::decode_block(expected),
+    ) else {
+        return false;
+    };
+    if !PASS_HASH_ITERS_ACCEPTED.contains(&iters) || expected.len() != PASS_HASH_LEN {
+        return false;
+    }
+    let mut hash = vec![0; expected.len()];
+    let ok = pkcs5::pbkdf2_hmac(
+        pass.as_bytes(),
+        &salt,
+        iters,
+        MessageDigest::sha256(),
+        &mut hash,
+    )
+    .is_ok();
+    ok && !expected.is_empty() && memcmp::eq(&hash, &expected)
+}
+
+/// Escapa `%`, `_` e `\` para uso literal num padrão `LIKE ... ESCAPE '\'`.
+pub fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+pub fn now_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+pub fn get_setting(db: &Db, key: &str) -> DbResult<Option<String>> {
+    let mut get_value = sqlite!(db, "SELECT value FROM settings WHERE key = ?", key);
+    if let State::Row = get_value.next()? {
+        Ok(Some(get_value.read::<String, _>("value")?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// `None` remove a configuração.
+pub fn set_setting(db: &Db, key: &str, value: Option<&str>) -> DbResult<()> {
+    let mut update_setting = match value {
+        Some(value) => sqlite!(
+            db,
+            "
+            INSERT INTO settings(key, value) VALUES(?, ?)
+            ON CONFLICT(key) DO UPDATE SET value = excluded.value
+            ",
+            key,
+            Payload(value),
+        ),
+        None => sqlite!(db, "DELETE FROM settings WHERE key = ?", key),
+    };
+    update_setting.next()?;
+    Ok(())
+}
+
+pub struct GlobalBan {
+    pub id: i64,
+    pub kind: BanKind,
+    /// nome de usuário ou endereço/faixa CIDR
+    pub target: String,
+    pub reason: String,
+    /// ms desde a época, `None` = permanente
+    pub expires: Option<i64>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BanKind {
+    User,
+    Ip,
+}
+
+impl BanKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BanKind::User => "user",
+            BanKind::Ip => "ip",
+        }
+    }
+}
+
+impl GlobalBan {
+    pub fn create(
+        db: &Db,
+        kind: BanKind,
+        target: &str,
+        reason: &str,
+        expires: Option<i64>,
+    ) -> DbResult<i64> {
+        let mut insert_ban = sqlite!(
+            db,
+            "
+            INSERT INTO global_bans(kind, target, reason, expires)
+            VALUES(?, ?, ?, ?)
+            RETURNING id
+            ",
+            kind.as_str(),
+            target,
+            reason,
+            expires,
+        );
+        insert_ban.next()?;
+        Ok(insert_ban.read::<i64, _>("id")?)
+    }
+
+    pub fn delete(db: &Db, id: i64) -> DbResult<bool> {
+        let mut delete_ban = sqlite!(
+            db,
+            "
+            DELETE FROM global_bans
+            WHERE id = ?
+            RETURNING (1)
+            ",
+            id,
+        );
+        Ok(delete_ban.next()? == State::Row)
+    }
+
+    /// Banimentos ainda em vigor, de um tipo ou de todos.
+    pub fn get_active(db: &Db, kind: Option<BanKind>) -> DbResult<Vec<GlobalBan>> {
+        let mut get_bans = sqlite!(
+            db,
+            "
+            SELECT id, kind, target, reason, expires FROM global_bans
+            WHERE (?1 IS NULL OR kind = ?1) AND (expires IS NULL OR expires > ?2)
+            ORDER BY id
+            ",
+            kind.map(BanKind::as_str),
+            now_millis(),
+        );
+        let mut bans = Vec::new();
+        while let State::Row = get_bans.next()? {
+            let kind = match get_bans.read::<String, _>("kind")?.as_str() {
+                "user" => BanKind::User,
+                _ => BanKind::Ip,
+            };
+            bans.push(GlobalBan {
+                id: get_bans.read::<i64, _>("id")?,
+                kind,
+                target: get_bans.read::<String, _>("target")?,
+                reason: get_bans.read::<String, _>("reason")?,
+                expires: get_bans.read::<Option<i64>, _>("expires")?,
+            });
+        }
+        Ok(bans)
+    }
+
+    pub fn find_user(db: &Db, name: &str) -> DbResult<Option<GlobalBan>> {
+        Ok(Self::get_active(db, Some(BanKind::User))?
+            .into_iter()
+            .find(|ban| ban.target == name))
+    }
+
+    pub fn find_ip(db: &Db, ip: std::net::IpAddr) -> DbResult<Option<GlobalBan>> {
+        Ok(Self::get_active(db, Some(BanKind::Ip))?
+            .into_iter()
+            .find(|ban| Cidr::parse(&ban.target).is_some_and(|cidr| cidr.contains(ip))))
+    }
+}
+
+/// Quem fez uma ação registrada em `audit_log`.
+pub struct Actor<'a> {
+    pub name: &'a str,
+    pub addr: &'a str,
+}
+
+impl Actor<'_> {
+    pub const CONSOLE: Actor<'static> = Actor {
+        name: "console",
+        addr: "local",
+    };
+    /// tarefas do próprio servidor, como a expiração de salas
+    pub const SERVER: Actor<'static> = Actor {
+        name: "server",
+        addr: "local",
+    };
+}
+
+pub struct AuditEntry {
+    pub id: i64,
+    pub at: i64,
+    pub actor: String,
+    pub addr: String,
+    pub action: String,
+    pub room_name: Option<String>,
+    pub target: Option<String>,
+    pub detail: String,
+}
+
+impl AuditEntry {
+    pub fn record(
+        db: &Db,
+        actor: &Actor,
+        action: &str,
+        room: Option<(&Room, &str)>,
+        target: Option<&str>,
+        detail: &str,
+    ) -> DbResult<()> {
+        let mut insert_entry = sqlite!(
+            db,
+            "
+            INSERT INTO audit_log(at, actor, addr, action, room_id, room_name, target, detail)
+            VALUES(?, ?, ?, ?, ?, ?, ?, ?)
+            ",
+            now_millis(),
+            actor.name,
+            actor.addr,
+            action,
+            room.map(|(room, _)| room.id),
+            room.map(|(_, room_name)| room_name),
+            target,
+            Payload(detail),
+        );
+        insert_entry.next()?;
+        Ok(())
+    }
+
+    /// Os `limit` registros mais recentes, do mais antigo ao mais novo. `room_id` filtra por uma
+    /// sala específica, `room_name` por todas as salas que já tiveram esse nome.
+    pub fn get_recent(
+        db: &Db,
+        room_id: Option<i64>,
+        room_name: Option<&str>,
+        limit: u32,
+    ) -> DbResult<Vec<AuditEntry>> {
+        let mut get_entries = sqlite!(
+            db,
+            "
+            SELECT * FROM (
+                SELECT id, at, actor, addr, action, room_name, target, detail FROM audit_log
+                WHERE (?1 IS NULL OR room_id = ?1) AND (?2 IS NULL OR room_name = ?2)
+                ORDER BY id DESC LIMIT ?3
+            ) ORDER BY id
+            ",
+            room_id,
+            room_name,
+            limit as i64,
+        );
+        let mut entries = Vec::new();
+        while let State::Row = get_entries.next()? {
+            entries.push(AuditEntry {
+                id: get_entries.read::<i64, _>("id")?,
+                at: get_entries.read::<i64, _>("at")?,
+                actor: get_entries.read::<String, _>("actor")?,
+                addr: get_entries.read::<String, _>("addr")?,
+                action: get_entries.read::<String, _>("action")?,
+                room_name: get_entries.read::<Option<String>, _>("room_name")?,
+                target: get_entries.read::<Option<String>, _>("target")?,
+                detail: get_entries.read::<String, _>("detail")?,
+            });
+        }
+        Ok(entries)
+    }
+}
+
+/// Apaga a mensagem recém-inserida se ninguém vai recebê-la.
+fn drop_undelivered(db: &Db, msg_id: i64) -> DbResult<()> {
+    let mut delete_message = sqlite!(
+        db,
+        "
+        DELETE FROM messages
+        WHERE id = ?1 AND NOT EXISTS (SELECT 1 FROM rel_user_msg WHERE msg_id = ?1)
+        ",
+        msg_id,
+    );
+    delete_message.next()?;
+    Ok(())
+}
+
+/// Grava uma cópia do banco em `path`, que não pode existir.
+pub fn dump(db: &Db, path: &std::path::Path) -> DbResult<()> {
+    let _lock = lock();
+    let mut vacuum = db.prepare("VACUUM INTO ?")?;
+    vacuum.bind((1, path.to_string_lossy().as_ref()))?;
+    vacuum.next()?;
+    Ok(())
+}
+
+/// Medidas do estado do banco para `/metrics`.
+pub struct Gauges {
+    pub users: i64,
+    pub rooms: i64,
+    pub pending_deliveries: i64,
+}
+
+impl Gauges {
+    pub fn get(db: &Db) -> DbResult<Gauges> {
+        let mut get_gauges = sqlite_no_log!(
+            db,
+            "
+            SELECT
+                (SELECT count(*) FROM users WHERE id != ?),
+                (SELECT count(*) FROM rooms),
+                (SELECT count(*) FROM rel_user_msg)
+            ",
+            SERVER_USER_ID,
+        );
+        get_gauges.next()?;
+        Ok(Gauges {
+            users: get_gauges.read::<i64, _>(0)?,
+            rooms: get_gauges.read::<i64, _>(1)?,
+            pending_deliveries: get_gauges.read::<i64, _>(2)?,
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct User {
     pub id: i64,
@@ -41,38 +591,143 @@ pub struct User {
 }
 
 impl User {
-    pub fn create(db: &Db, name: &str) -> Option<i64> {
+    pub fn create(db: &Db, name: &str, addr: &str) -> DbResult<Option<i64>> {
         let mut insert_user = sqlite!(
             db,
             "
-            INSERT INTO users(name)
-            VALUES(?)
+            INSERT INTO users(name, addr)
+            VALUES(?, ?)
             RETURNING id
             ",
             name,
+            addr,
         );
-        if let State::Row = insert_user.next().unwrap() {
-            Some(insert_user.read::<i64, _>("id").unwrap())
+        if let State::Row = insert_user.next()? {
+            Ok(Some(insert_user.read::<i64, _>("id")?))
         } else {
-            None
+            Ok(None)
         }
     }
 
-    pub fn delete_cascade(&self, db: &Db) {
+    pub fn delete_cascade(&self, db: &Db) -> DbResult<()> {
         let mut delete_cascade = sqlite!(db, "DELETE FROM users WHERE id = ?", self.id);
-        delete_cascade.next().unwrap();
+        delete_cascade.next()?;
+        Ok(())
     }
 
-    pub fn get_id(db: &Db, name: &str) -> Option<i64> {
+    pub fn get_id(db: &Db, name: &str) -> DbResult<Option<i64>> {
         let mut get_id = sqlite!(db, "SELECT id FROM users WHERE name = ?", name);
-        if let State::Row = get_id.next().unwrap() {
-            Some(get_id.read::<i64, _>("id").unwrap())
+        if let State::Row = get_id.next()? {
+            Ok(Some(get_id.read::<i64, _>("id")?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Nomes de todos os usuários conectados.
+    pub fn get_all(db: &Db) -> DbResult<Vec<String>> {
+        let mut get_names = sqlite!(
+            db,
+            "SELECT name FROM users WHERE id != ? ORDER BY name",
+            SERVER_USER_ID,
+        );
+        let mut names = Vec::new();
+        while let State::Row = get_names.next()? {
+            names.push(get_names.read::<String, _>("name")?);
+        }
+        Ok(names)
+    }
+
+    /// `(nome, endereço IP)` de todos os usuários conectados.
+    pub fn get_all_addrs(db: &Db) -> DbResult<Vec<(String, String)>> {
+        let mut get_addrs = sqlite!(
+            db,
+            "SELECT name, addr FROM users WHERE id != ?",
+            SERVER_USER_ID,
+        );
+        let mut addrs = Vec::new();
+        while let State::Row = get_addrs.next()? {
+            let name = get_addrs.read::<String, _>("name")?;
+            let addr = get_addrs.read::<String, _>("addr")?;
+            addrs.push((name, addr));
+        }
+        Ok(addrs)
+    }
+
+    /// Marca a conexão de `name` para ser encerrada, ver [`User::is_kicked`].
+    pub fn kick(db: &Db, name: &str) -> DbResult<bool> {
+        let mut update_kicked = sqlite!(
+            db,
+            "
+            UPDATE users SET kicked = TRUE
+            WHERE name = ? AND id != ?
+            RETURNING (1)
+            ",
+            name,
+            SERVER_USER_ID,
+        );
+        Ok(update_kicked.next()? == State::Row)
+    }
+
+    pub fn is_kicked(&self, db: &Db) -> DbResult<bool> {
+        let mut get_kicked =
+            sqlite_no_log!(db, "SELECT (1) FROM users WHERE id = ? AND kicked", self.id,);
+        Ok(get_kicked.next()? == State::Row)
+    }
+
+    pub fn get_name(db: &Db, id: i64) -> DbResult<Option<String>> {
+        let mut get_name = sqlite!(db, "SELECT name FROM users WHERE id = ?", id);
+        if let State::Row = get_name.next()? {
+            Ok(Some(get_name.read::<String, _>("name")?))
         } else {
-            None
+            Ok(None)
         }
     }
 
-    pub fn send_to(db: &Db, user_id: i64, msg: &str) {
+    /// Menções não lidas do usuário em salas das quais ainda é membro, marcando todas como
+    /// lidas. Retorna `(sala, mensagem)`.
+    pub fn take_mentions(db: &Db, user_id: i64) -> DbResult<Vec<(String, RoomMsg)>> {
+        transaction(db, || {
+            let mut get_mentions = sqlite!(
+                db,
+                "
+                SELECT room.name AS room_name,
+                    msg.id, msg.parent_id, msg.user_name, msg.msg, msg.reply_count
+                FROM mentions mention
+                INNER JOIN rel_room_user member
+                    ON member.room_id = mention.room_id AND member.user_id = mention.user_id
+                INNER JOIN rooms room ON room.id = mention.room_id
+                INNER JOIN room_msgs msg ON msg.id = mention.msg_id
+                WHERE mention.user_id = ?
+                ORDER BY mention.id
+                ",
+                user_id,
+            );
+            let mut mentions = Vec::new();
+            while let State::Row = get_mentions.next()? {
+                let room_name = get_mentions.read::<String, _>("room_name")?;
+                let room_msg = RoomMsg {
+                    id: get_mentions.read::<i64, _>("id")?,
+                    parent_id: get_mentions.read::<Option<i64>, _>("parent_id")?,
+                    user_name: get_mentions.read::<String, _>("user_name")?,
+                    msg: get_mentions.read::<String, _>("msg")?,
+                    reply_count: get_mentions.read::<i64, _>("reply_count")?,
+                };
+                mentions.push((room_name, room_msg));
+            }
+
+            let mut delete_mentions =
+                sqlite!(db, "DELETE FROM mentions WHERE user_id = ?", user_id);
+            delete_mentions.next()?;
+            Ok(mentions)
+        })
+    }
+
+    pub fn send_to(db: &Db, user_id: i64, msg: &str) -> DbResult<()> {
+        transaction(db, || User::insert_send_to(db, user_id, msg))
+    }
+
+    fn insert_send_to(db: &Db, user_id: i64, msg: &str) -> DbResult<()> {
         let mut insert_message = sqlite!(
             db,
             "
@@ -80,10 +735,10 @@ impl User {
             VALUES(?)
             RETURNING id
             ",
-            msg,
+            Payload(msg),
         );
-        insert_message.next().unwrap();
-        let msg_id = insert_message.read::<i64, _>("id").unwrap();
+        insert_message.next()?;
+        let msg_id = insert_message.read::<i64, _>("id")?;
 
         let mut insert_rel_user_msg = sqlite!(
             db,
@@ -94,15 +749,51 @@ impl User {
             user_id,
             msg_id,
         );
-        insert_rel_user_msg.next().unwrap();
+        insert_rel_user_msg.next()?;
+        Ok(())
+    }
+
+    /// Entrega `msg` a todos os usuários conectados.
+    pub fn send_to_all(db: &Db, msg: &str) -> DbResult<()> {
+        transaction(db, || User::insert_send_to_all(db, msg))
     }
 
-    pub fn drain_msgs(db: &'static Db, user_id: i64) -> Vec<String> {
+    fn insert_send_to_all(db: &Db, msg: &str) -> DbResult<()> {
+        let mut insert_message = sqlite!(
+            db,
+            "
+            INSERT INTO messages (msg)
+            VALUES(?)
+            RETURNING id
+            ",
+            Payload(msg),
+        );
+        insert_message.next()?;
+        let msg_id = insert_message.read::<i64, _>("id")?;
+
+        let mut insert_rel_user_msg = sqlite!(
+            db,
+            "
+            INSERT INTO rel_user_msg(user_id, msg_id)
+            SELECT id, ? FROM users
+            WHERE id != ?
+            ",
+            msg_id,
+            SERVER_USER_ID,
+        );
+        insert_rel_user_msg.next()?;
+        drop_undelivered(db, msg_id)
+    }
+
+    /// Entregas pendentes do usuário, na ordem em que foram feitas. Uma entrega pode ter mais
+    /// de uma linha (`ID_MENSAGEM` + `MENSAGEM`), que chegam juntas e nessa ordem.
+    pub fn drain_msgs(db: &'static Db, user_id: i64) -> DbResult<Vec<String>> {
         let mut get_msgs = sqlite_no_log!(
             db,
             "
             SELECT id, msg FROM view_user_msgs
             WHERE user_id = ?
+            ORDER BY id
             ",
             user_id,
         );
@@ -116,41 +807,119 @@ impl User {
         );
 
         let mut msgs = Vec::new();
-        while let State::Row = get_msgs.next().unwrap() {
-            let rel_id = get_msgs.read::<i64, _>(0).unwrap();
-            let msg = get_msgs.read::<String, _>(1).unwrap();
-            delete_rel.reset().unwrap();
-            delete_rel.bind((1, rel_id)).unwrap();
-            delete_rel.next().unwrap();
+        while let State::Row = get_msgs.next()? {
+            let rel_id = get_msgs.read::<i64, _>(0)?;
+            let msg = get_msgs.read::<String, _>(1)?;
+            delete_rel.reset()?;
+            delete_rel.bind((1, rel_id))?;
+            delete_rel.next()?;
             msgs.push(msg);
         }
-        msgs
+        Ok(msgs)
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RoomOrder {
+    Name = 0,
+    /// mais membros primeiro
+    Members = 1,
+    /// atividade mais recente primeiro
+    Activity = 2,
+}
+
+pub struct RoomPage {
+    pub names: Vec<String>,
+    /// token para pedir a próxima página, se houver
+    pub next: Option<String>,
+}
+
+/// Posição da última sala de uma página, serializada como token opaco (base64).
+struct RoomCursor {
+    order: RoomOrder,
+    key: i64,
+    name: String,
+}
+
+impl RoomCursor {
+    fn encode(&self) -> String {
+        let cursor = format!("{} {} {}", self.order as i64, self.key, self.name);
+        base64::encode_block(cursor.as_bytes())
+    }
+
+    fn decode(token: &str, order: RoomOrder) -> Option<Self> {
+        let cursor = base64::decode_block(token).ok()?;
+        let cursor = String::from_utf8(cursor).ok()?;
+        let mut split = cursor.splitn(3, ' ');
+        if split.next()? != (order as i64).to_string() {
+            return None;
+        }
+        let key = split.next()?.parse().ok()?;
+        let name = split.next()?.to_string();
+        Some(Self { order, key, name })
+    }
+}
+
+pub struct RoomMsg {
+    pub id: i64,
+    /// raiz da thread, `None` se a própria mensagem for raiz
+    pub parent_id: Option<i64>,
+    pub user_name: String,
+    pub msg: String,
+    pub reply_count: i64,
+}
+
+pub struct RoomSummary {
+    pub name: String,
+    pub admin_name: String,
+    pub private: bool,
+    pub persistent: bool,
+    pub members: i64,
+}
+
 pub struct Room {
     pub id: i64,
     pub admin: i64,
 }
 
+/// Resultado de `Room::join`.
+pub enum Join {
+    Joined(Room),
+    /// posição na fila de espera
+    Queued(i64),
+    NotFound,
+    Banned,
+    AlreadyMember,
+    WrongPass,
+    Full,
+}
+
 impl Room {
-    pub fn create(db: &Db, name: &str, private: bool, pass: &str, admin_id: i64) -> bool {
+    pub fn create(
+        db: &Db,
+        name: &str,
+        private: bool,
+        pass: &PassHash,
+        admin_id: i64,
+        persistent: bool,
+    ) -> DbResult<bool> {
         let mut insert_room = sqlite!(
             db,
             "
-            INSERT OR IGNORE INTO rooms(name, private, pass, admin)
-            VALUES(?, ?, ?, ?)
+            INSERT OR IGNORE INTO rooms(name, private, pass, admin, persistent)
+            VALUES(?, ?, ?, ?, ?)
             RETURNING (1)
             ",
             name,
             private as i64,
-            pass,
-            admin_id
+            Secret(&pass.0),
+            admin_id,
+            persistent as i64,
         );
-        insert_room.next().unwrap() == State::Row
+        Ok(insert_room.next()? == State::Row)
     }
 
-    pub fn get(db: &Db, name: &str) -> Option<Room> {
+    pub fn get(db: &Db, name: &str) -> DbResult<Option<Room>> {
         let mut select_room = sqlite!(
             db,
             "
@@ -159,83 +928,174 @@ impl Room {
             ",
             name,
         );
-        if let State::Row = select_room.next().unwrap() {
-            let id = select_room.read::<i64, _>("id").unwrap();
-            let admin = select_room.read::<i64, _>("admin").unwrap();
-            Some(Room { id, admin })
+        if let State::Row = select_room.next()? {
+            let id = select_room.read::<i64, _>("id")?;
+            let admin = select_room.read::<i64, _>("admin")?;
+            Ok(Some(Room { id, admin }))
         } else {
-            None
+            Ok(None)
         }
     }
 
-    pub fn get_all(db: &'static Db) -> impl Iterator<Item = String> {
-        let mut get_room_names = sqlite!(
+    /// Uma página de salas públicas cujo nome casa com `pattern` (LIKE), depois de `after`.
+    /// Retorna `None` se o token de continuação for inválido.
+    pub fn search(
+        db: &Db,
+        pattern: &str,
+        order: RoomOrder,
+        after: Option<&str>,
+        limit: u32,
+    ) -> DbResult<Option<RoomPage>> {
+        let after = match after {
+            Some(token) => match RoomCursor::decode(token, order) {
+                Some(cursor) => Some(cursor),
+                None => return Ok(None),
+            },
+            None => None,
+        };
+        let (after_key, after_name) = match &after {
+            Some(cursor) => (Some(cursor.key), cursor.name.as_str()),
+            None => (None, ""),
+        };
+        let mut get_rooms = sqlite!(
             db,
             "
-            SELECT name FROM rooms
-            WHERE private = FALSE
+            SELECT name, sort_key FROM (
+                SELECT room.name, CASE ?1
+                    WHEN 0 THEN 0
+                    WHEN 1 THEN -(
+                        SELECT COUNT(*) FROM rel_room_user rel
+                        WHERE rel.room_id = room.id
+                    )
+                    ELSE -room.last_activity
+                END AS sort_key
+                FROM rooms room
+                WHERE room.private = FALSE AND room.name LIKE ?2 ESCAPE '\\'
+            )
+            WHERE ?3 IS NULL OR (sort_key, name) > (?3, ?4)
+            ORDER BY sort_key, name
+            LIMIT ?5
             ",
+            order as i64,
+            pattern,
+            after_key,
+            after_name,
+            limit as i64 + 1,
         );
 
-        std::iter::from_fn(move || {
-            if get_room_names.next().unwrap() == State::Row {
-                Some(get_room_names.read::<String, _>("name").unwrap())
-            } else {
-                None
+        let mut page = RoomPage {
+            names: Vec::new(),
+            next: None,
+        };
+        let mut last = None;
+        while let State::Row = get_rooms.next()? {
+            if page.names.len() == limit as usize {
+                page.next = last.map(|cursor: RoomCursor| cursor.encode());
+                break;
             }
-        })
+            let name = get_rooms.read::<String, _>("name")?;
+            let key = get_rooms.read::<i64, _>("sort_key")?;
+            last = Some(RoomCursor {
+                order,
+                key,
+                name: name.clone(),
+            });
+            page.names.push(name);
+        }
+        Ok(Some(page))
     }
 
-    pub fn get_all_from_member(
-        db: &'static Db,
-        user_id: i64,
-    ) -> impl Iterator<Item = (Self, String)> {
+    /// Todas as salas, inclusive privadas, para o console.
+    pub fn get_summaries(db: &Db) -> DbResult<Vec<RoomSummary>> {
+        let mut get_rooms = sqlite!(
+            db,
+            "
+            SELECT room.name, admin.name AS admin_name, room.private, room.persistent, (
+                SELECT COUNT(*) FROM rel_room_user rel
+                WHERE rel.room_id = room.id
+            ) AS members
+            FROM rooms room
+            INNER JOIN users admin ON admin.id = room.admin
+            ORDER BY room.name
+            ",
+        );
+        let mut rooms = Vec::new();
+        while let State::Row = get_rooms.next()? {
+            rooms.push(RoomSummary {
+                name: get_rooms.read::<String, _>("name")?,
+                admin_name: get_rooms.read::<String, _>("admin_name")?,
+                private: get_rooms.read::<i64, _>("private")? != 0,
+                persistent: get_rooms.read::<i64, _>("persistent")? != 0,
+                members: get_rooms.read::<i64, _>("members")?,
+            });
+        }
+        Ok(rooms)
+    }
+
+    pub fn get_all_from_member(db: &Db, user_id: i64) -> DbResult<Vec<(Self, String)>> {
         let mut get_ids = sqlite!(
             db,
             "
             SELECT room.id, room.admin, room.name FROM rel_room_user rel
-            INNER JOIN rooms room ON room.id = rel.room_id AND room.admin != ?
+            INNER JOIN rooms room ON room.id = rel.room_id
+                AND (room.admin != ? OR room.persistent)
             WHERE rel.user_id = ?
             ",
             user_id,
             user_id,
         );
-        std::iter::from_fn(move || {
-            if let Ok(State::Row) = get_ids.next() {
-                let id = get_ids.read::<i64, _>("id").unwrap();
-                let admin = get_ids.read::<i64, _>("admin").unwrap();
-                let name = get_ids.read::<String, _>("name").unwrap();
-                Some((Room { id, admin }, name))
-            } else {
-                None
-            }
-        })
+        let mut rooms = Vec::new();
+        while let State::Row = get_ids.next()? {
+            let id = get_ids.read::<i64, _>("id")?;
+            let admin = get_ids.read::<i64, _>("admin")?;
+            let name = get_ids.read::<String, _>("name")?;
+            rooms.push((Room { id, admin }, name));
+        }
+        Ok(rooms)
     }
-    pub fn get_all_from_admin(
-        db: &'static Db,
-        user_id: i64,
-    ) -> impl Iterator<Item = (Room, String)> {
+
+    /// Salas que fecham junto com a conexão do admin (não persistentes).
+    pub fn get_all_from_admin(db: &Db, user_id: i64) -> DbResult<Vec<(Room, String)>> {
         let mut get_ids = sqlite!(
             db,
             "
             SELECT id, admin, name FROM rooms
-            WHERE admin = ?
+            WHERE admin = ? AND persistent = FALSE
             ",
             user_id
         );
-        std::iter::from_fn(move || {
-            if let Ok(State::Row) = get_ids.next() {
-                let id = get_ids.read::<i64, _>("id").unwrap();
-                let admin = get_ids.read::<i64, _>("admin").unwrap();
-                let name = get_ids.read::<String, _>("name").unwrap();
-                Some((Room { id, admin }, name))
-            } else {
-                None
-            }
-        })
+        let mut rooms = Vec::new();
+        while let State::Row = get_ids.next()? {
+            let id = get_ids.read::<i64, _>("id")?;
+            let admin = get_ids.read::<i64, _>("admin")?;
+            let name = get_ids.read::<String, _>("name")?;
+            rooms.push((Room { id, admin }, name));
+        }
+        Ok(rooms)
     }
 
-    pub fn get_users(&self, db: &'static Db) -> impl Iterator<Item = String> {
+    /// Salas persistentes sem atividade há pelo menos `idle_secs` segundos.
+    pub fn get_expired(db: &Db, idle_secs: i64) -> DbResult<Vec<(Room, String)>> {
+        let mut get_ids = sqlite!(
+            db,
+            "
+            SELECT id, admin, name FROM rooms
+            WHERE persistent
+                AND last_activity <= CAST(strftime('%s', 'now') AS INTEGER) - ?
+            ",
+            idle_secs,
+        );
+        let mut rooms = Vec::new();
+        while let State::Row = get_ids.next()? {
+            let id = get_ids.read::<i64, _>("id")?;
+            let admin = get_ids.read::<i64, _>("admin")?;
+            let name = get_ids.read::<String, _>("name")?;
+            rooms.push((Room { id, admin }, name));
+        }
+        Ok(rooms)
+    }
+
+    pub fn get_users(&self, db: &Db) -> DbResult<Vec<String>> {
         let mut get_user_names = sqlite!(
             db,
             "
@@ -245,16 +1105,14 @@ impl Room {
             self.id,
         );
 
-        std::iter::from_fn(move || {
-            if let Ok(State::Row) = get_user_names.next() {
-                Some(get_user_names.read::<String, _>("name").unwrap())
-            } else {
-                None
-            }
-        })
+        let mut names = Vec::new();
+        while let State::Row = get_user_names.next()? {
+            names.push(get_user_names.read::<String, _>("name")?);
+        }
+        Ok(names)
     }
 
-    pub fn is_member(&self, db: &Db, user_id: i64) -> bool {
+    pub fn is_member(&self, db: &Db, user_id: i64) -> DbResult<bool> {
         let mut get_member = sqlite!(
             db,
             "
@@ -264,14 +1122,14 @@ impl Room {
             self.id,
             user_id,
         );
-        get_member.next().unwrap() == State::Row
+        Ok(get_member.next()? == State::Row)
     }
 
     pub fn is_admin(&self, user_id: i64) -> bool {
         self.admin == user_id
     }
 
-    pub fn is_banned(&self, db: &Db, user_id: i64) -> bool {
+    pub fn is_banned(&self, db: &Db, user_id: i64) -> DbResult<bool> {
         let mut get_banned = sqlite!(
             db,
             "
@@ -281,23 +1139,390 @@ impl Room {
             self.id,
             user_id,
         );
-        get_banned.next().unwrap() == State::Row
+        Ok(get_banned.next()? == State::Row)
+    }
+
+    /// Hash guardado da senha, `None` se a sala não existe mais.
+    fn get_pass(&self, db: &Db) -> DbResult<Option<String>> {
+        let mut get_pass = sqlite!(
+            db,
+            "
+            SELECT pass FROM rooms
+            WHERE id = ?
+            ",
+            self.id,
+        );
+        if let State::Row = get_pass.next()? {
+            Ok(Some(get_pass.read::<String, _>("pass")?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn is_full(&self, db: &Db) -> DbResult<bool> {
+        let mut get_full = sqlite!(
+            db,
+            "
+            SELECT (1) FROM rooms room
+            WHERE room.id = ? AND room.max_members != 0 AND room.max_members <= (
+                SELECT COUNT(*) FROM rel_room_user rel
+                WHERE rel.room_id = room.id
+            )
+            ",
+            self.id,
+        );
+        Ok(get_full.next()? == State::Row)
+    }
+
+    pub fn get_topic(&self, db: &Db) -> DbResult<String> {
+        let mut get_topic = sqlite!(db, "SELECT topic FROM rooms WHERE id = ?", self.id);
+        if let State::Row = get_topic.next()? {
+            Ok(get_topic.read::<String, _>("topic")?)
+        } else {
+            Ok(String::new())
+        }
+    }
+
+    /// Falha se a sala for privada e a nova senha for vazia.
+    pub fn set_pass(&self, db: &Db, pass: &PassHash) -> DbResult<bool> {
+        let mut update_pass = sqlite!(
+            db,
+            "
+            UPDATE rooms SET pass = ?1
+            WHERE id = ?2 AND (private = FALSE OR ?1 != '')
+            RETURNING (1)
+            ",
+            Secret(&pass.0),
+            self.id,
+        );
+        Ok(update_pass.next()? == State::Row)
+    }
+
+    /// Falha se a sala passar a ser privada sem ter senha.
+    pub fn set_private(&self, db: &Db, private: bool) -> DbResult<bool> {
+        let mut update_private = sqlite!(
+            db,
+            "
+            UPDATE rooms SET private = ?1
+            WHERE id = ?2 AND (?1 = FALSE OR pass != '')
+            RETURNING (1)
+            ",
+            private as i64,
+            self.id,
+        );
+        Ok(update_private.next()? == State::Row)
+    }
+
+    /// Falha se já existir uma sala com o novo nome (índice `room_names`).
+    pub fn rename(&self, db: &Db, name: &str) -> DbResult<bool> {
+        let mut update_name = sqlite!(
+            db,
+            "
+            UPDATE OR IGNORE rooms SET name = ?
+            WHERE id = ?
+            RETURNING (1)
+            ",
+            name,
+            self.id,
+        );
+        Ok(update_name.next()? == State::Row)
+    }
+
+    pub fn set_topic(&self, db: &Db, topic: &str) -> DbResult<()> {
+        let mut update_topic = sqlite!(
+            db,
+            "UPDATE rooms SET topic = ? WHERE id = ?",
+            Payload(topic),
+            self.id,
+        );
+        update_topic.next()?;
+        Ok(())
+    }
+
+    pub fn set_persistent(&self, db: &Db, persistent: bool) -> DbResult<()> {
+        let mut update_persistent = sqlite!(
+            db,
+            "UPDATE rooms SET persistent = ? WHERE id = ?",
+            persistent as i64,
+            self.id,
+        );
+        update_persistent.next()?;
+        Ok(())
+    }
+
+    pub fn set_slow_mode(&self, db: &Db, secs: u32) -> DbResult<()> {
+        let mut update_slow_mode = sqlite!(
+            db,
+            "UPDATE rooms SET slow_mode = ? WHERE id = ?",
+            secs as i64,
+            self.id,
+        );
+        update_slow_mode.next()?;
+        Ok(())
+    }
+
+    /// Registra uma postagem respeitando o modo lento; `Err` tem os ms até poder postar.
+    pub fn try_post(&self, db: &Db, user_id: i64) -> DbResult<Result<(), i64>> {
+        let now = now_millis();
+        let mut update_last_post = sqlite!(
+            db,
+            "
+            UPDATE rel_room_user SET last_post = ?3
+            WHERE room_id = ?1 AND user_id = ?2 AND last_post + 1000 * (
+                SELECT slow_mode FROM rooms WHERE id = ?1
+            ) <= ?3
+            RETURNING (1)
+            ",
+            self.id,
+            user_id,
+            now,
+        );
+        if update_last_post.next()? == State::Row {
+            return Ok(Ok(()));
+        }
+        let mut get_next_post = sqlite!(
+            db,
+            "
+            SELECT rel.last_post + 1000 * room.slow_mode AS next_post
+            FROM rel_room_user rel
+            INNER JOIN rooms room ON room.id = rel.room_id
+            WHERE rel.room_id = ? AND rel.user_id = ?
+            ",
+            self.id,
+            user_id,
+        );
+        if let State::Row = get_next_post.next()? {
+            Ok(Err(get_next_post.read::<i64, _>("next_post")? - now))
+        } else {
+            Ok(Err(0))
+        }
+    }
+
+    pub fn set_max_members(&self, db: &Db, max_members: u32) -> DbResult<()> {
+        let mut update_max_members = sqlite!(
+            db,
+            "UPDATE rooms SET max_members = ? WHERE id = ?",
+            max_members as i64,
+            self.id,
+        );
+        update_max_members.next()?;
+        Ok(())
+    }
+
+    /// Guarda uma mensagem no histórico da sala e retorna seu id.
+    pub fn post(&self, db: &Db, user_name: &str, msg: &str) -> DbResult<i64> {
+        let mut insert_room_msg = sqlite!(
+            db,
+            "
+            INSERT INTO room_msgs(room_id, user_name, msg)
+            VALUES(?, ?, ?)
+            RETURNING id
+            ",
+            self.id,
+            user_name,
+            Payload(msg),
+        );
+        insert_room_msg.next()?;
+        Ok(insert_room_msg.read::<i64, _>("id")?)
+    }
+
+    /// Apaga as threads mais antigas do histórico além das `keep` mensagens mais recentes.
+    /// Uma thread só sai inteira, e fica se tiver resposta recente, mensagem fixada ou menção
+    /// não lida, então o limite é aproximado.
+    pub fn prune_history(&self, db: &Db, keep: u32) -> DbResult<()> {
+        let mut delete_old = sqlite!(
+            db,
+            "
+            WITH cutoff(id) AS (
+                SELECT id FROM room_msgs
+                WHERE room_id = ?1
+                ORDER BY id DESC
+                LIMIT 1 OFFSET ?2
+            ),
+            kept(root_id) AS (
+                SELECT coalesce(msg.parent_id, msg.id) FROM room_msgs msg
+                WHERE msg.room_id = ?1 AND (
+                    msg.id > (SELECT id FROM cutoff)
+                    OR msg.id IN (SELECT msg_id FROM room_pins WHERE room_id = ?1)
+                    OR msg.id IN (SELECT msg_id FROM mentions WHERE room_id = ?1)
+                )
+            )
+            DELETE FROM room_msgs
+            WHERE room_id = ?1 AND parent_id IS NULL
+                AND id <= (SELECT id FROM cutoff)
+                AND id NOT IN (SELECT root_id FROM kept)
+            ",
+            self.id,
+            keep as i64,
+        );
+        delete_old.next()?;
+        Ok(())
+    }
+
+    /// Guarda uma resposta na thread de `parent_id` e retorna `(id, raiz da thread)`.
+    /// Respostas a respostas vão para a mesma thread. `None` se o pai não for desta sala.
+    pub fn reply(
+        &self,
+        db: &Db,
+        user_name: &str,
+        parent_id: i64,
+        msg: &str,
+    ) -> DbResult<Option<(i64, i64)>> {
+        let mut insert_room_msg = sqlite!(
+            db,
+            "
+            INSERT INTO room_msgs(room_id, user_name, parent_id, msg)
+            SELECT ?1, ?2, COALESCE(parent.parent_id, parent.id), ?3
+            FROM room_msgs parent
+            WHERE parent.id = ?4 AND parent.room_id = ?1
+            RETURNING id, parent_id
+            ",
+            self.id,
+            user_name,
+            Payload(msg),
+            parent_id,
+        );
+        if let State::Row = insert_room_msg.next()? {
+            let id = insert_room_msg.read::<i64, _>("id")?;
+            let parent_id = insert_room_msg.read::<i64, _>("parent_id")?;
+            Ok(Some((id, parent_id)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn has_msg(&self, db: &Db, msg_id: i64) -> DbResult<bool> {
+        let mut get_msg = sqlite!(
+            db,
+            "SELECT (1) FROM room_msgs WHERE id = ? AND room_id = ?",
+            msg_id,
+            self.id,
+        );
+        Ok(get_msg.next()? == State::Row)
+    }
+
+    /// A raiz da thread que contém `msg_id`, seguida das respostas em ordem.
+    pub fn get_thread(&self, db: &Db, msg_id: i64) -> DbResult<Vec<RoomMsg>> {
+        let mut get_msgs = sqlite!(
+            db,
+            "
+            WITH root AS (
+                SELECT COALESCE(parent_id, id) AS id FROM room_msgs
+                WHERE id = ?2 AND room_id = ?1
+            )
+            SELECT id, parent_id, user_name, msg, reply_count FROM room_msgs
+            WHERE id = (SELECT id FROM root) OR parent_id = (SELECT id FROM root)
+            ORDER BY id
+            ",
+            self.id,
+            msg_id,
+        );
+        let mut msgs = Vec::new();
+        while let State::Row = get_msgs.next()? {
+            msgs.push(RoomMsg {
+                id: get_msgs.read::<i64, _>("id")?,
+                parent_id: get_msgs.read::<Option<i64>, _>("parent_id")?,
+                user_name: get_msgs.read::<String, _>("user_name")?,
+                msg: get_msgs.read::<String, _>("msg")?,
+                reply_count: get_msgs.read::<i64, _>("reply_count")?,
+            });
+        }
+        Ok(msgs)
+    }
+
+    /// Registra uma menção não lida a um membro. Só para membros: a menção dá acesso ao
+    /// texto da mensagem, e some quando ele sai da sala ou desconecta.
+    pub fn add_mention(&self, db: &Db, user_id: i64, msg_id: i64) -> DbResult<()> {
+        let mut insert_mention = sqlite!(
+            db,
+            "
+            INSERT OR IGNORE INTO mentions(user_id, room_id, msg_id)
+            VALUES(?, ?, ?)
+            ",
+            user_id,
+            self.id,
+            msg_id,
+        );
+        insert_mention.next()?;
+        Ok(())
+    }
+
+    /// Falha se a mensagem não for desta sala ou já estiver fixada.
+    pub fn pin(&self, db: &Db, msg_id: i64) -> DbResult<bool> {
+        let mut insert_pin = sqlite!(
+            db,
+            "
+            INSERT OR IGNORE INTO room_pins(room_id, msg_id)
+            SELECT room_id, id FROM room_msgs
+            WHERE id = ? AND room_id = ?
+            RETURNING (1)
+            ",
+            msg_id,
+            self.id,
+        );
+        Ok(insert_pin.next()? == State::Row)
+    }
+
+    pub fn unpin(&self, db: &Db, msg_id: i64) -> DbResult<bool> {
+        let mut delete_pin = sqlite!(
+            db,
+            "
+            DELETE FROM room_pins
+            WHERE room_id = ? AND msg_id = ?
+            RETURNING (1)
+            ",
+            self.id,
+            msg_id,
+        );
+        Ok(delete_pin.next()? == State::Row)
     }
 
-    pub fn check_pass(&self, db: &Db, pass: &str) -> bool {
-        let mut compare_pass = sqlite!(
+    pub fn get_pins(&self, db: &Db) -> DbResult<Vec<RoomMsg>> {
+        let mut get_msgs = sqlite!(
             db,
             "
-            SELECT (1) FROM rooms
-            WHERE id = ? AND pass = ?
+            SELECT msg.id, msg.parent_id, msg.user_name, msg.msg, msg.reply_count
+            FROM room_pins pin
+            INNER JOIN room_msgs msg ON msg.id = pin.msg_id
+            WHERE pin.room_id = ?
+            ORDER BY msg.id
             ",
             self.id,
-            pass,
         );
-        compare_pass.next().unwrap() == State::Row
+        let mut msgs = Vec::new();
+        while let State::Row = get_msgs.next()? {
+            msgs.push(RoomMsg {
+                id: get_msgs.read::<i64, _>("id")?,
+                parent_id: get_msgs.read::<Option<i64>, _>("parent_id")?,
+                user_name: get_msgs.read::<String, _>("user_name")?,
+                msg: get_msgs.read::<String, _>("msg")?,
+                reply_count: get_msgs.read::<i64, _>("reply_count")?,
+            });
+        }
+        Ok(msgs)
+    }
+
+    pub fn broadcast(&self, db: &Db, msg: &str, except0: i64, except1: i64) -> DbResult<()> {
+        self.broadcast_except(db, msg, &[except0, except1])
+    }
+
+    /// Entrega `msg` a todos os membros menos `except`. A mensagem e as entregas são gravadas
+    /// juntas: nunca fica mensagem sem destinatário nem entrega pela metade.
+    pub fn broadcast_except(&self, db: &Db, msg: &str, except: &[i64]) -> DbResult<()> {
+        transaction(db, || self.insert_broadcast(db, msg, except))
     }
 
-    pub fn broadcast(&self, db: &Db, msg: &str, except0: i64, except1: i64) {
+    fn insert_broadcast(&self, db: &Db, msg: &str, except: &[i64]) -> DbResult<()> {
+        let mut touch_room = sqlite!(
+            db,
+            "
+            UPDATE rooms SET last_activity = CAST(strftime('%s', 'now') AS INTEGER)
+            WHERE id = ?
+            ",
+            self.id,
+        );
+        touch_room.next()?;
+
         let mut insert_message = sqlite!(
             db,
             "
@@ -305,20 +1530,18 @@ impl Room {
             VALUES(?)
             RETURNING id
             ",
-            msg,
+            Payload(msg),
         );
-        insert_message.next().unwrap();
-        let msg_id = insert_message.read::<i64, _>("id").unwrap();
+        insert_message.next()?;
+        let msg_id = insert_message.read::<i64, _>("id")?;
 
         let mut get_room_users = sqlite!(
             db,
             "
             SELECT user_id FROM rel_room_user
-            WHERE room_id = ? AND user_id NOT IN (?, ?)
+            WHERE room_id = ?
             ",
             self.id,
-            except0,
-            except1,
         );
 
         let mut insert_rel_user_msg = sqlite!(
@@ -329,16 +1552,20 @@ impl Room {
             ",
         );
 
-        while let Ok(State::Row) = get_room_users.next() {
-            let user_id = get_room_users.read::<i64, _>("user_id").unwrap();
-            insert_rel_user_msg.reset().unwrap();
-            insert_rel_user_msg.bind((1, user_id)).unwrap();
-            insert_rel_user_msg.bind((2, msg_id)).unwrap();
-            insert_rel_user_msg.next().unwrap();
+        while let State::Row = get_room_users.next()? {
+            let user_id = get_room_users.read::<i64, _>("user_id")?;
+            if except.contains(&user_id) {
+                continue;
+            }
+            insert_rel_user_msg.reset()?;
+            insert_rel_user_msg.bind((1, user_id))?;
+            insert_rel_user_msg.bind((2, msg_id))?;
+            insert_rel_user_msg.next()?;
         }
+        drop_undelivered(db, msg_id)
     }
 
-    pub fn delete_cascade(&self, db: &Db) {
+    pub fn delete_cascade(&self, db: &Db) -> DbResult<()> {
         let mut delete_cascade = sqlite!(
             db,
             "
@@ -347,10 +1574,59 @@ impl Room {
             ",
             self.id,
         );
-        delete_cascade.next().unwrap();
+        delete_cascade.next()?;
+        Ok(())
     }
 
-    pub fn add_user(&self, db: &Db, user_id: i64) {
+    /// Entra em `name`, ou na fila se estiver cheia e `queue`. As checagens e a entrada são
+    /// uma transação só, então a sala não é fechada nem lota entre uma e outra.
+    ///
+    /// A senha é conferida antes, fora da transação, por ser lenta. Se a sala foi fechada e
+    /// recriada com o mesmo nome nesse meio tempo, a entrada é recusada como `NotFound`; se a
+    /// senha mudou, como `WrongPass`.
+    pub async fn join(
+        db: &Db,
+        name: &str,
+        user_id: i64,
+        pass: &str,
+        queue: bool,
+    ) -> DbResult<Join> {
+        let Some(room) = Room::get(db, name)? else {
+            return Ok(Join::NotFound);
+        };
+        let Some(stored) = room.get_pass(db)? else {
+            return Ok(Join::NotFound);
+        };
+        let pass_ok = verify_pass_blocking(&stored, pass).await;
+        transaction(db, || {
+            match Room::get(db, name)? {
+                Some(current) if current.id == room.id => {}
+                _ => return Ok(Join::NotFound),
+            }
+            if room.is_banned(db, user_id)? {
+                return Ok(Join::Banned);
+            }
+            if room.is_member(db, user_id)? {
+                return Ok(Join::AlreadyMember);
+            }
+            if !pass_ok || room.get_pass(db)?.as_ref() != Some(&stored) {
+                return Ok(Join::WrongPass);
+            }
+            if room.is_full(db)? {
+                if !queue {
+                    return Ok(Join::Full);
+                }
+                return Ok(Join::Queued(room.enqueue(db, user_id)?));
+            }
+            room.add_user(db, user_id)?;
+            Ok(Join::Joined(Room {
+                id: room.id,
+                admin: room.admin,
+            }))
+        })
+    }
+
+    pub fn add_user(&self, db: &Db, user_id: i64) -> DbResult<()> {
         let mut insert_rel = sqlite!(
             db,
             "
@@ -360,10 +1636,11 @@ impl Room {
             self.id,
             user_id,
         );
-        insert_rel.next().unwrap();
+        insert_rel.next()?;
+        Ok(())
     }
 
-    pub fn kick(&self, db: &Db, user_id: i64) -> bool {
+    pub fn kick(&self, db: &Db, user_id: i64) -> DbResult<bool> {
         let mut delete_rel = sqlite!(
             db,
             "
@@ -374,10 +1651,85 @@ impl Room {
             self.id,
             user_id,
         );
-        delete_rel.next().unwrap() == State::Row
+        Ok(delete_rel.next()? == State::Row)
+    }
+
+    /// Coloca o usuário no fim da fila de espera, retorna sua posição (1 = próximo).
+    fn enqueue(&self, db: &Db, user_id: i64) -> DbResult<i64> {
+        let mut insert_rel = sqlite!(
+            db,
+            "
+            INSERT OR IGNORE INTO rel_room_queue(room_id, user_id)
+            VALUES(?, ?)
+            ",
+            self.id,
+            user_id,
+        );
+        insert_rel.next()?;
+
+        let mut get_position = sqlite!(
+            db,
+            "
+            SELECT COUNT(*) AS position FROM rel_room_queue
+            WHERE room_id = ?1 AND id <= (
+                SELECT id FROM rel_room_queue
+                WHERE room_id = ?1 AND user_id = ?2
+            )
+            ",
+            self.id,
+            user_id,
+        );
+        get_position.next()?;
+        Ok(get_position.read::<i64, _>("position")?)
+    }
+
+    pub fn dequeue(&self, db: &Db, user_id: i64) -> DbResult<bool> {
+        let mut delete_rel = sqlite!(
+            db,
+            "
+            DELETE FROM rel_room_queue
+            WHERE room_id = ? AND user_id = ?
+            RETURNING (1)
+            ",
+            self.id,
+            user_id,
+        );
+        Ok(delete_rel.next()? == State::Row)
+    }
+
+    /// Admite usuários da fila enquanto houver vagas, retorna os ids admitidos.
+    pub fn admit_queued(&self, db: &Db) -> DbResult<Vec<i64>> {
+        transaction(db, || self.pop_queue(db))
+    }
+
+    fn pop_queue(&self, db: &Db) -> DbResult<Vec<i64>> {
+        let mut admitted = Vec::new();
+        while !self.is_full(db)? {
+            let mut pop_queue = sqlite!(
+                db,
+                "
+                DELETE FROM rel_room_queue
+                WHERE id = (
+                    SELECT id FROM rel_room_queue
+                    WHERE room_id = ?
+                    ORDER BY id
+                    LIMIT 1
+                )
+                RETURNING user_id
+                ",
+                self.id,
+            );
+            let State::Row = pop_queue.next()? else {
+                break;
+            };
+            let user_id = pop_queue.read::<i64, _>("user_id")?;
+            self.add_user(db, user_id)?;
+            admitted.push(user_id);
+        }
+        Ok(admitted)
     }
 
-    pub fn ban(&self, db: &Db, user_id: i64) -> bool {
+    pub fn ban(&self, db: &Db, user_id: i64) -> DbResult<bool> {
         let mut insert_rel = sqlite!(
             db,
             "
@@ -388,6 +1740,6 @@ impl Room {
             self.id,
             user_id,
         );
-        insert_rel.next().unwrap() == State::Row
+        Ok(insert_rel.next()? == State::Row)
     }
 }
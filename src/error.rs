@@ -0,0 +1,140 @@
+//! Catálogo de erros do protocolo.
+//!
+//! Toda resposta de erro é `ERRO <CÓDIGO> <texto>`, às vezes com um complemento depois do
+//! texto (motivo do banimento, tempo de espera). O código é estável e serve para o cliente
+//! decidir o que fazer; o texto é para pessoas, pode mudar e vem no idioma da sessão
+//! (`i18n.rs`).
+//!
+//! | código                    | quando                                                   |
+//! |---------------------------|----------------------------------------------------------|
+//! | `BANIDO_SERVIDOR`         | endereço ou nome banido do servidor, seguido do motivo   |
+//! | `USUARIO_EXISTE`          | `REGISTRO` com nome em uso                               |
+//! | `NOME_DIFERENTE`          | `AUTENTICACAO` com nome diferente do `REGISTRO`          |
+//! | `CHAVE_INVALIDA`          | esperava `CHAVE_SIMETRICA`                               |
+//! | `USUARIO_NAO_CRIADO`      | registro falhou no banco                                 |
+//! | `IDIOMA_DESCONHECIDO`     | `REGISTRO` com idioma sem catálogo                       |
+//! | `SERVIDOR_CHEIO`          | limite de conexões atingido, antes mesmo do `REGISTRO`   |
+//! | `COMANDO_INVALIDO`        | comando desconhecido ou com argumentos inválidos         |
+//! | `LIMITE_COMANDOS`         | limite de comandos excedido, seguido do tempo de espera  |
+//! | `ABUSO`                   | excesso sustentado de comandos, a conexão é encerrada    |
+//! | `CONTINUACAO_INVALIDA`    | token de `APOS` em `LISTAR_SALAS` inválido               |
+//! | `SALA_NAO_ENCONTRADA`     | sala não existe                                          |
+//! | `SALA_EXISTE`             | nome de sala em uso                                      |
+//! | `NAO_MEMBRO`              | comando exige ser membro da sala                         |
+//! | `JA_MEMBRO`               | já é membro da sala                                      |
+//! | `NAO_ADMIN`               | comando exige ser admin da sala                          |
+//! | `ADMIN_DEVE_FECHAR`       | admin tentou sair da própria sala                        |
+//! | `SENHA_OBRIGATORIA`       | sala privada sem senha                                   |
+//! | `SENHA_INCORRETA`         | senha da sala errada                                     |
+//! | `BANIDO_SALA`             | banido da sala                                           |
+//! | `SALA_CHEIA`              | sala no limite de membros e sem `FILA`                   |
+//! | `MENSAGEM_VAZIA`          | mensagem sem texto                                       |
+//! | `MODO_LENTO`              | modo lento da sala, seguido do tempo de espera           |
+//! | `MENSAGEM_NAO_ENCONTRADA` | mensagem não existe na sala                              |
+//! | `NAO_FIXAVEL`             | mensagem não existe ou já está fixada                    |
+//! | `NAO_FIXADA`              | mensagem não está fixada                                 |
+//! | `USUARIO_NAO_ENCONTRADO`  | usuário não existe                                       |
+//! | `AUTO_BANIMENTO`          | admin tentou banir a si mesmo                            |
+//! | `OPERACAO_REJEITADA`      | o banco recusou o comando (restrição violada)            |
+//! | `SERVIDOR_OCUPADO`        | banco ocupado, vale tentar de novo                       |
+//! | `ERRO_INTERNO`            | falha interna, a conexão é encerrada                     |
+use std::fmt;
+
+use crate::i18n::Language;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    ServerBanned,
+    UserExists,
+    NameMismatch,
+    BadKeyExchange,
+    UserNotCreated,
+    UnknownLanguage,
+    ServerFull,
+    UnknownCommand,
+    RateLimited,
+    Flooding,
+    BadCursor,
+    RoomNotFound,
+    RoomExists,
+    NotMember,
+    AlreadyMember,
+    NotAdmin,
+    AdminMustClose,
+    PassRequired,
+    WrongPass,
+    RoomBanned,
+    RoomFull,
+    EmptyMessage,
+    SlowMode,
+    MessageNotFound,
+    NotPinnable,
+    NotPinned,
+    UserNotFound,
+    SelfBan,
+    Rejected,
+    Busy,
+    Internal,
+}
+
+impl ErrorCode {
+    /// Código estável e texto padrão.
+    fn parts(self) -> (&'static str, &'static str) {
+        match self {
+            ErrorCode::ServerBanned => ("BANIDO_SERVIDOR", "banido do servidor"),
+            ErrorCode::UserExists => ("USUARIO_EXISTE", "usuário já existe"),
+            ErrorCode::NameMismatch => ("NOME_DIFERENTE", "nome de usuário difere"),
+            ErrorCode::BadKeyExchange => ("CHAVE_INVALIDA", "transmissao de chave simetrica"),
+            ErrorCode::UserNotCreated => ("USUARIO_NAO_CRIADO", "não foi possível criar usuário"),
+            ErrorCode::UnknownLanguage => ("IDIOMA_DESCONHECIDO", "idioma desconhecido"),
+            ErrorCode::ServerFull => ("SERVIDOR_CHEIO", "servidor cheio"),
+            ErrorCode::UnknownCommand => ("COMANDO_INVALIDO", "comando nao reconhecido"),
+            ErrorCode::RateLimited => ("LIMITE_COMANDOS", "limite de comandos excedido"),
+            ErrorCode::Flooding => ("ABUSO", "desconectado por abuso"),
+            ErrorCode::BadCursor => ("CONTINUACAO_INVALIDA", "token de continuação inválido"),
+            ErrorCode::RoomNotFound => ("SALA_NAO_ENCONTRADA", "sala não encontrada"),
+            ErrorCode::RoomExists => ("SALA_EXISTE", "sala já existe"),
+            ErrorCode::NotMember => ("NAO_MEMBRO", "não é membro da sala"),
+            ErrorCode::AlreadyMember => ("JA_MEMBRO", "já está na sala"),
+            ErrorCode::NotAdmin => ("NAO_ADMIN", "não é admin"),
+            ErrorCode::AdminMustClose => ("ADMIN_DEVE_FECHAR", "admin deve fechar a sala"),
+            ErrorCode::PassRequired => ("SENHA_OBRIGATORIA", "sala privada deve ter uma senha"),
+            ErrorCode::WrongPass => ("SENHA_INCORRETA", "senha incorreta"),
+            ErrorCode::RoomBanned => ("BANIDO_SALA", "banido da sala"),
+            ErrorCode::RoomFull => ("SALA_CHEIA", "sala cheia"),
+            ErrorCode::EmptyMessage => ("MENSAGEM_VAZIA", "mensagem vazia"),
+            ErrorCode::SlowMode => ("MODO_LENTO", "modo lento"),
+            ErrorCode::MessageNotFound => ("MENSAGEM_NAO_ENCONTRADA", "mensagem não encontrada"),
+            ErrorCode::NotPinnable => ("NAO_FIXAVEL", "mensagem não encontrada ou já fixada"),
+            ErrorCode::NotPinned => ("NAO_FIXADA", "mensagem não está fixada"),
+            ErrorCode::UserNotFound => ("USUARIO_NAO_ENCONTRADO", "usuário não encontrado"),
+            ErrorCode::SelfBan => ("AUTO_BANIMENTO", "não pode banir a si mesmo"),
+            ErrorCode::Rejected => ("OPERACAO_REJEITADA", "operação rejeitada"),
+            ErrorCode::Busy => ("SERVIDOR_OCUPADO", "servidor ocupado, tente novamente"),
+            ErrorCode::Internal => ("ERRO_INTERNO", "erro interno do servidor"),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        self.parts().0
+    }
+
+    pub fn text(self) -> &'static str {
+        self.parts().1
+    }
+
+    /// `ERRO <código> <texto>` com o texto em `language`, sem quebra de linha.
+    pub fn localized(self, language: &Language) -> Localized<'_> {
+        Localized(self, language)
+    }
+}
+
+pub struct Localized<'a>(ErrorCode, &'a Language);
+
+impl fmt::Display for Localized<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Localized(code, language) = *self;
+        let text = language.get(code.as_str(), code.text());
+        write!(f, "ERRO {} {}", code.as_str(), text)
+    }
+}
@@ -0,0 +1,144 @@
+//! Textos do servidor traduzidos conforme o idioma escolhido no `REGISTRO`.
+//!
+//! Só o texto para pessoas é traduzido: palavras-chave das respostas e códigos de erro são
+//! sempre os mesmos. O português é o padrão embutido no código (`ErrorCode::text`); os outros
+//! idiomas vêm de catálogos `<idioma>.txt`, um `CHAVE = texto` por linha, com `#` para
+//! comentários. As chaves são os códigos de `error.rs` mais as de `Text`.
+//!
+//! O inglês vem embutido (`lang/en.txt`). Arquivos em `CHAT_LANG_DIR` acrescentam idiomas ou
+//! substituem textos dos embutidos.
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Idioma usado quando o cliente não escolhe nenhum.
+pub const DEFAULT: &str = "pt";
+
+const BUILTIN: [(&str, &str); 1] = [("en", include_str!("./lang/en.txt"))];
+
+static LANGUAGES: OnceLock<Vec<Language>> = OnceLock::new();
+
+pub struct Language {
+    pub name: String,
+    texts: HashMap<String, String>,
+}
+
+/// Textos que não são mensagens de erro.
+#[derive(Clone, Copy)]
+pub enum Text {
+    /// complemento de `LIMITE_COMANDOS` e `MODO_LENTO`, antes dos segundos
+    RetryIn,
+    /// motivo de `DESCONECTADO` após `/kick`
+    Kicked,
+    /// motivo de `DESCONECTADO` após `CHAT_IDLE_TIMEOUT_SECS` sem comandos
+    Idle,
+}
+
+impl Text {
+    fn parts(self) -> (&'static str, &'static str) {
+        match self {
+            Text::RetryIn => ("TENTE_EM", "tente em"),
+            Text::Kicked => ("EXPULSO", "expulso pelo operador"),
+            Text::Idle => ("INATIVO", "inativo por muito tempo"),
+        }
+    }
+}
+
+impl Language {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            texts: HashMap::new(),
+        }
+    }
+
+    /// Texto de `key` neste idioma, ou `default` (português) se o catálogo não o tiver.
+    pub fn get<'a>(&'a self, key: &str, default: &'a str) -> &'a str {
+        self.texts.get(key).map_or(default, String::as_str)
+    }
+
+    pub fn text(&self, text: Text) -> &str {
+        let (key, default) = text.parts();
+        self.get(key, default)
+    }
+
+    fn merge(&mut self, source: &str, catalog: &str) {
+        for (i, line) in catalog.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((key, text)) if !key.trim().is_empty() => {
+                    self.texts
+                        .insert(key.trim().to_string(), text.trim().to_string());
+                }
+                _ => warn!(Server, "{}:{}: expected `KEY = text`", source, i + 1),
+            }
+        }
+    }
+}
+
+/// Carrega os catálogos embutidos e os de `dir`. Chamado uma vez, na inicialização.
+pub fn load(dir: Option<&Path>) {
+    let mut languages = vec![Language::new(DEFAULT)];
+    for (name, catalog) in BUILTIN {
+        let mut language = Language::new(name);
+        language.merge(name, catalog);
+        languages.push(language);
+    }
+    if let Some(dir) = dir {
+        load_dir(&mut languages, dir);
+    }
+    let names: Vec<&str> = languages
+        .iter()
+        .map(|language| language.name.as_str())
+        .collect();
+    info!(Server, "languages: {}", names.join(", "));
+    let _ = LANGUAGES.set(languages);
+}
+
+fn load_dir(languages: &mut Vec<Language>, dir: &Path) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!(Server, "cannot read language dir {:?}: {}", dir, err);
+            return;
+        }
+    };
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.extension().is_none_or(|ext| ext != "txt") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let catalog = match std::fs::read_to_string(&path) {
+            Ok(catalog) => catalog,
+            Err(err) => {
+                warn!(Server, "cannot read {:?}: {}", path, err);
+                continue;
+            }
+        };
+        let index = match languages.iter().position(|language| language.name == name) {
+            Some(index) => index,
+            None => {
+                languages.push(Language::new(name));
+                languages.len() - 1
+            }
+        };
+        languages[index].merge(&path.to_string_lossy(), &catalog);
+    }
+}
+
+pub fn find(name: &str) -> Option<&'static Language> {
+    LANGUAGES
+        .get()?
+        .iter()
+        .find(|language| language.name == name)
+}
+
+pub fn default() -> &'static Language {
+    static FALLBACK: OnceLock<Language> = OnceLock::new();
+    find(DEFAULT).unwrap_or_else(|| FALLBACK.get_or_init(|| Language::new(DEFAULT)))
+}
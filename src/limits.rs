@@ -0,0 +1,116 @@
+//! Limites de conexões: total, por IP e novas conexões por segundo por IP.
+//!
+//! Endereços em `CHAT_CONN_ALLOWLIST` não são limitados nem contados.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::ratelimit::{RateLimiter, Verdict};
+
+/// Com mais IPs que isso na tabela, os que não têm conexão aberta são descartados.
+const PRUNE_ABOVE: usize = 1024;
+/// Tempo sem conexões novas depois do qual o balde de um IP já está cheio de novo.
+const PRUNE_IDLE: Duration = Duration::from_secs(60);
+
+pub struct ConnLimits {
+    config: &'static Config,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    open: u32,
+    per_ip: HashMap<IpAddr, IpState>,
+}
+
+struct IpState {
+    open: u32,
+    rate: RateLimiter,
+    last: Instant,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Reject {
+    /// `CHAT_MAX_CONNECTIONS` atingido
+    Full,
+    /// `CHAT_MAX_CONNECTIONS_PER_IP` atingido
+    PerIp,
+    /// conexões novas rápido demais
+    Rate,
+}
+
+/// Vaga ocupada por uma conexão, devolvida quando descartada.
+pub struct ConnGuard {
+    limits: &'static ConnLimits,
+    ip: Option<IpAddr>,
+}
+
+impl ConnLimits {
+    pub fn new(config: &'static Config) -> Self {
+        Self {
+            config,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn admit(&'static self, ip: IpAddr) -> Result<ConnGuard, Reject> {
+        let ip = ip.to_canonical();
+        let config = self.config;
+        if config.conn_allowlist.iter().any(|cidr| cidr.contains(ip)) {
+            return Ok(ConnGuard {
+                limits: self,
+                ip: None,
+            });
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.per_ip.len() > PRUNE_ABOVE {
+            state
+                .per_ip
+                .retain(|_, ip_state| ip_state.open > 0 || ip_state.last.elapsed() < PRUNE_IDLE);
+        }
+        if config.max_connections.is_some_and(|max| state.open >= max) {
+            return Err(Reject::Full);
+        }
+        let ip_state = state.per_ip.entry(ip).or_insert_with(|| IpState {
+            open: 0,
+            // sem desconexão por abuso, só recusa
+            rate: RateLimiter::new(
+                config.connect_burst_per_ip,
+                config.connect_rate_per_ip,
+                u32::MAX,
+            ),
+            last: Instant::now(),
+        });
+        ip_state.last = Instant::now();
+        if config
+            .max_connections_per_ip
+            .is_some_and(|max| ip_state.open >= max)
+        {
+            return Err(Reject::PerIp);
+        }
+        if !matches!(ip_state.rate.check(), Verdict::Allow) {
+            return Err(Reject::Rate);
+        }
+        ip_state.open += 1;
+        state.open += 1;
+        Ok(ConnGuard {
+            limits: self,
+            ip: Some(ip),
+        })
+    }
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        let Some(ip) = self.ip else {
+            return;
+        };
+        let mut state = self.limits.state.lock().unwrap();
+        state.open -= 1;
+        if let Some(ip_state) = state.per_ip.get_mut(&ip) {
+            ip_state.open -= 1;
+        }
+    }
+}
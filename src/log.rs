@@ -0,0 +1,288 @@
+//! Log com níveis por subsistema, em texto ou JSON, na saída de erro.
+//!
+//! Cada linha leva a conexão e o usuário da task que a escreveu (`enter_conn`, `set_user`).
+//! Os níveis vêm de `CHAT_LOG` (ex.: `info,db=debug`) e podem ser trocados pelo console com
+//! `/log`.
+//!
+//! Por padrão o conteúdo das mensagens não vai para o log: comandos aparecem só com o nome e
+//! o tamanho (`Command`) e corpos de mensagem no SQL como `<N bytes>` (`db::Payload`). O
+//! conteúdo completo só aparece com `CHAT_LOG_PAYLOADS=true` ou `/log payloads on`.
+use std::cell::Cell;
+use std::fmt::{self, Write as _};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    const ALL: [Level; 6] = [
+        Level::Off,
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    fn parse(text: &str) -> Option<Level> {
+        Self::ALL.into_iter().find(|level| level.as_str() == text)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Target {
+    /// ciclo de vida do servidor, tarefas de fundo
+    Server = 0,
+    /// conexões TCP e linhas cruas
+    Net = 1,
+    /// handshake RSA/AES e falhas de decifração
+    Crypto = 2,
+    /// queries SQL
+    Db = 3,
+    /// comandos do protocolo
+    Protocol = 4,
+    /// console de administração
+    Admin = 5,
+}
+
+impl Target {
+    const ALL: [Target; 6] = [
+        Target::Server,
+        Target::Net,
+        Target::Crypto,
+        Target::Db,
+        Target::Protocol,
+        Target::Admin,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Target::Server => "server",
+            Target::Net => "net",
+            Target::Crypto => "crypto",
+            Target::Db => "db",
+            Target::Protocol => "protocol",
+            Target::Admin => "admin",
+        }
+    }
+
+    fn parse(text: &str) -> Option<Target> {
+        Self::ALL.into_iter().find(|target| target.as_str() == text)
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const INFO: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static LEVELS: [AtomicU8; Target::ALL.len()] = [INFO; Target::ALL.len()];
+static JSON: AtomicBool = AtomicBool::new(false);
+static PAYLOADS: AtomicBool = AtomicBool::new(false);
+static NEXT_CONN: AtomicU64 = AtomicU64::new(1);
+
+async_std::task_local! {
+    /// `(conexão, usuário)` da task atual, 0 = nenhum
+    static CONTEXT: Cell<(u64, i64)> = Cell::new((0, 0));
+}
+
+/// Dá um ID novo à conexão atendida pela task atual.
+pub fn enter_conn() -> u64 {
+    let conn = NEXT_CONN.fetch_add(1, Ordering::Relaxed);
+    let _ = CONTEXT.try_with(|context| context.set((conn, 0)));
+    conn
+}
+
+pub fn set_user(user_id: i64) {
+    let _ = CONTEXT.try_with(|context| context.set((context.get().0, user_id)));
+}
+
+pub fn enabled(target: Target, level: Level) -> bool {
+    level as u8 <= LEVELS[target as usize].load(Ordering::Relaxed)
+}
+
+/// Aplica `nível` (todos os alvos) ou `alvo=nível`, separados por vírgula.
+pub fn configure(spec: &str) -> Result<(), String> {
+    let mut changes = Vec::new();
+    for item in spec
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+    {
+        match item.split_once('=') {
+            None => {
+                let level = Level::parse(item).ok_or(format!("unknown level {:?}", item))?;
+                changes.extend(Target::ALL.map(|target| (target, level)));
+            }
+            Some((target, level)) => {
+                let target = Target::parse(target).ok_or(format!("unknown target {:?}", target))?;
+                let level = Level::parse(level).ok_or(format!("unknown level {:?}", level))?;
+                changes.push((target, level));
+            }
+        }
+    }
+    // nada muda se algum item for inválido
+    for (target, level) in changes {
+        LEVELS[target as usize].store(level as u8, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn set_payloads(payloads: bool) {
+    PAYLOADS.store(payloads, Ordering::Relaxed);
+}
+
+/// Se o conteúdo das mensagens pode aparecer no log.
+pub fn payloads() -> bool {
+    PAYLOADS.load(Ordering::Relaxed)
+}
+
+/// Linha do protocolo que aparece no log como `NOME <N bytes>`, a não ser com `payloads()`.
+pub struct Command<'a>(pub &'a str);
+
+impl fmt::Display for Command<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if payloads() {
+            return write!(f, "{:?}", self.0);
+        }
+        let size = self.0.trim_end().len();
+        // linhas cifradas não têm nome de comando, só o tamanho
+        match self.0.split_whitespace().next() {
+            Some(name) if name.bytes().all(|b| b.is_ascii_uppercase() || b == b'_') => {
+                write!(f, "{} <{} bytes>", name, size)
+            }
+            _ => write!(f, "<{} bytes>", size),
+        }
+    }
+}
+
+/// Níveis atuais no formato aceito por `configure`.
+pub fn describe() -> String {
+    let mut out = String::new();
+    for target in Target::ALL {
+        let level = LEVELS[target as usize].load(Ordering::Relaxed);
+        let sep = if out.is_empty() { "" } else { "," };
+        let _ = write!(
+            out,
+            "{}{}={}",
+            sep,
+            target.as_str(),
+            Level::ALL[level as usize].as_str()
+        );
+    }
+    let format = if JSON.load(Ordering::Relaxed) {
+        "json"
+    } else {
+        "text"
+    };
+    let _ = write!(out, " format={}", format);
+    if payloads() {
+        out.push_str(" payloads=on");
+    }
+    out
+}
+
+pub fn write(target: Target, level: Level, args: fmt::Arguments) {
+    let (conn, user) = CONTEXT.try_with(Cell::get).unwrap_or((0, 0));
+    let mut line = String::new();
+    if JSON.load(Ordering::Relaxed) {
+        let _ = write!(
+            line,
+            "{{\"ts\":{},\"level\":\"{}\",\"target\":\"{}\"",
+            crate::db::now_millis(),
+            level.as_str(),
+            target.as_str()
+        );
+        if conn != 0 {
+            let _ = write!(line, ",\"conn\":{}", conn);
+        }
+        if user != 0 {
+            let _ = write!(line, ",\"user\":{}", user);
+        }
+        line.push_str(",\"msg\":\"");
+        escape_json(&mut line, &args.to_string());
+        line.push_str("\"}");
+    } else {
+        let _ = write!(
+            line,
+            "({})\t{}\t",
+            target.as_str().to_uppercase(),
+            level.as_str().to_uppercase()
+        );
+        if conn != 0 {
+            let _ = write!(line, "c{} ", conn);
+        }
+        if user != 0 {
+            let _ = write!(line, "u{} ", user);
+        }
+        let _ = write!(line, "{}", args);
+    }
+    eprintln!("{}", line);
+}
+
+fn escape_json(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+}
+
+macro_rules! log {
+    ($target:ident, $level:ident, $($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Target::$target, $crate::log::Level::$level) {
+            $crate::log::write(
+                $crate::log::Target::$target,
+                $crate::log::Level::$level,
+                format_args!($($arg)*),
+            );
+        }
+    };
+}
+
+macro_rules! error {
+    ($target:ident, $($arg:tt)*) => { log!($target, Error, $($arg)*) };
+}
+
+macro_rules! warn {
+    ($target:ident, $($arg:tt)*) => { log!($target, Warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($target:ident, $($arg:tt)*) => { log!($target, Info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($target:ident, $($arg:tt)*) => { log!($target, Debug, $($arg)*) };
+}
+
+macro_rules! trace {
+    ($target:ident, $($arg:tt)*) => { log!($target, Trace, $($arg)*) };
+}
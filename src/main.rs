@@ -1,54 +1,100 @@
 #![feature(str_split_whitespace_remainder)]
-use async_std::net::{SocketAddr, TcpListener};
+use async_std::net::{SocketAddr, TcpListener, TcpStream};
 use async_std::prelude::*;
 use async_std::task;
+use core::time::Duration;
 use openssl::{base64, rsa};
 use sqlite::ConnectionThreadSafe as Db;
 use std::fmt::Write as _;
 use std::str::FromStr as _;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 
 type RsaKey = rsa::Rsa<openssl::pkey::Private>;
 type AesKey = [u8; 32];
 
+#[macro_use]
+mod log;
+mod admin;
+mod cidr;
+mod config;
+use config::Config;
 mod parse;
-use parse::Command;
+mod ratelimit;
+mod rooms;
+use parse::{Command, RoomFilter, RoomSetting};
+use ratelimit::{RateLimiter, Verdict};
 mod db;
+mod error;
+use error::ErrorCode;
+mod i18n;
+use i18n::Text;
+mod limits;
+use limits::{ConnLimits, Reject};
+mod metrics;
+use metrics::METRICS;
+mod shutdown;
 mod socket;
+mod sql;
 use socket::Stream;
 
+/// menções notificadas por mensagem
+const MAX_MENTIONS: usize = 10;
+const ROOM_PAGE_DEFAULT: u32 = 50;
+const ROOM_PAGE_MAX: u32 = 100;
+/// registros mandados por `LISTAR_AUDITORIA`
+const AUDIT_PAGE: u32 = 50;
+/// de quanto em quanto tempo o handshake confere o prazo e o encerramento
+const HANDSHAKE_POLL: Duration = Duration::from_millis(100);
+
 #[derive(Debug)]
 enum IoError {
     Failed,
     Closed,
     BadCrypto,
     Timeout,
+    Db(db::DbError),
 }
 
-async fn admin(db: &Db) -> Option<()> {
-    let mut line = String::new();
-    loop {
-        print!("SQL> ");
-        async_std::io::stdout().flush().await.unwrap();
-        line.clear();
-        async_std::io::stdin().read_line(&mut line).await.ok()?;
-        let query = db.iterate(&line, |pairs| {
-            let mut pairs = pairs.iter();
-            if let Some(&(name, value)) = pairs.next() {
-                print!("| {}={:?} | ", name, value.unwrap());
-            }
-            for &(name, value) in pairs {
-                print!("{}={:?} | ", name, value.unwrap());
-            }
-            println!();
-            true
-        });
-        if query.is_err() {
-            println!("SQL error");
-            continue;
+impl From<db::DbError> for IoError {
+    fn from(err: db::DbError) -> Self {
+        IoError::Db(err)
+    }
+}
+
+/// Resposta ao cliente para uma falha do banco e se a conexão deve ser encerrada.
+fn db_failure(err: &db::DbError) -> (ErrorCode, bool) {
+    match err {
+        db::DbError::Constraint(_) => {
+            warn!(Db, "{}", err);
+            (ErrorCode::Rejected, false)
+        }
+        db::DbError::Busy => {
+            warn!(Db, "{}", err);
+            (ErrorCode::Busy, false)
+        }
+        db::DbError::Other(_) => {
+            error!(Db, "{}", err);
+            (ErrorCode::Internal, true)
         }
     }
 }
 
+/// Desembrulha um `DbResult` dentro do laço de comandos; numa falha responde ao cliente,
+/// marca a conexão para fechar se a falha for grave e segue para o próximo comando.
+macro_rules! db_try {
+    ($stream:ident, $closed:ident, $label:lifetime, $result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(err) => {
+                let (reply, fatal) = db_failure(&err);
+                $closed |= $stream.write_error(reply).await.is_err() || fatal;
+                continue $label;
+            }
+        }
+    };
+}
+
 async fn auth_client(
     db: &Db,
     stream: &mut Stream,
@@ -58,7 +104,7 @@ async fn auth_client(
     msg: &mut String,
 ) -> Result<db::User, IoError> {
     /*
-     * REGISTRO usuario             .. REGISTRO_OK
+     * REGISTRO usuario [idioma]    .. REGISTRO_OK
      * AUTENTICACAO usuario         .. CHAVE_PUBLICA rsa_key
      * CHAVE_SIMETRICA RSA(aes_key) ..
      * AES(comandos ...)            .. AES(respostas ...)
@@ -66,12 +112,24 @@ async fn auth_client(
 
     // registro
     stream.block_read_plain_line(buf).await?;
-    let name = parse::command_register(&buf)
-        .ok_or(IoError::Failed)?
-        .to_string();
+    #[allow(clippy::needless_borrow)]
+    let (name, language) = parse::command_register(&buf).ok_or(IoError::Failed)?;
+    let name = name.to_string();
+    let Some(language) = i18n::find(language.unwrap_or(i18n::DEFAULT)) else {
+        stream.write_plain_error(ErrorCode::UnknownLanguage).await?;
+        return Err(IoError::Failed);
+    };
+    stream.set_language(language);
 
-    if db::User::get_id(db, &name).is_some() {
-        stream.write_plain_msg("ERRO usuário já existe\n").await?;
+    if let Some(ban) = db::GlobalBan::find_user(db, &name)? {
+        msg.clear();
+        let banned = ErrorCode::ServerBanned.localized(language);
+        let _ = writeln!(msg, "{}: {}", banned, ban.reason);
+        stream.write_plain_msg(msg).await?;
+        return Err(IoError::Failed);
+    }
+    if db::User::get_id(db, &name)?.is_some() {
+        stream.write_plain_error(ErrorCode::UserExists).await?;
         return Err(IoError::Failed);
     }
     stream.write_plain_msg("REGISTRO_OK\n").await?;
@@ -79,20 +137,21 @@ async fn auth_client(
     // autenticação RSA
     stream.block_read_plain_line(buf).await?;
     // println!("recebeu {buf:?}");
+    #[allow(clippy::needless_borrow)]
     if parse::command_auth(&buf) != Some(name.as_str()) {
-        stream
-            .write_plain_msg("ERRO nome de usuário difere\n")
-            .await?;
+        stream.write_plain_error(ErrorCode::NameMismatch).await?;
         return Err(IoError::Failed);
     }
     msg.clear();
     writeln!(msg, "CHAVE_PUBLICA {}", pub_key).map_err(|_| IoError::Closed)?;
+    #[allow(clippy::needless_borrow)]
     stream.write_plain_msg(&msg).await?;
     // println!("enviou {msg:?}");
 
     // transmissão chave simétrica
     stream.block_read_plain_line(buf).await?;
     // println!("recebeu {buf:?}");
+    #[allow(clippy::needless_borrow)]
     if let Some(aes_key) = parse::command_aes_key(&buf) {
         let Ok(dec_aes_key0) = base64::decode_block(aes_key) else {
             // println!("decodificar base64 AES");
@@ -118,70 +177,350 @@ async fn auth_client(
         };
         stream.set_aes_key(dec_aes_key);
     } else {
-        stream
-            .write_plain_msg("ERRO transmissao de chave simetrica\n")
-            .await?;
+        stream.write_plain_error(ErrorCode::BadKeyExchange).await?;
         return Err(IoError::Failed);
     };
 
-    let Some(id) = db::User::create(db, &name) else {
-        stream
-            .write_plain_msg("ERRO não foi possível criar usuário\n")
-            .await?;
+    let addr = stream.peer_addr().ip().to_canonical().to_string();
+    let Some(id) = db::User::create(db, &name, &addr)? else {
+        stream.write_plain_error(ErrorCode::UserNotCreated).await?;
         return Err(IoError::Failed);
     };
+    // nada pode falhar depois daqui, senão o nome ficaria reservado por um usuário sem conexão
     Ok(db::User { id, name })
 }
 
-async fn handle_client(
-    db: &'static Db,
-    mut stream: socket::Stream,
-    rsa_key: RsaKey,
+/// Repete o handshake até um usuário ser criado. `None` se a conexão deve ser encerrada.
+async fn authenticate(
+    db: &Db,
+    stream: &mut Stream,
+    rsa_key: &RsaKey,
     pub_key: &'static str,
-) {
-    let mut buf = String::new();
-    let mut msg = String::new();
-
-    let current_user = loop {
-        match auth_client(&db, &mut stream, &rsa_key, pub_key, &mut buf, &mut msg).await {
+    buf: &mut String,
+    msg: &mut String,
+) -> Option<db::User> {
+    loop {
+        match auth_client(db, stream, rsa_key, pub_key, buf, msg).await {
             Ok(user) => {
-                break user;
+                return Some(user);
+            }
+            Err(IoError::Failed) => {
+                metrics::inc(&METRICS.handshake_rejected);
+                continue;
             }
-            Err(IoError::Failed) => continue,
             Err(IoError::Timeout) => unreachable!(),
+            Err(IoError::Db(err)) => {
+                // auth_client só cria o usuário no último passo, então uma falha do banco
+                // não deixa nada para trás e o cliente pode tentar de novo
+                let (reply, fatal) = db_failure(&err);
+                if stream.write_plain_error(reply).await.is_err() || fatal {
+                    return None;
+                }
+                continue;
+            }
             Err(IoError::Closed) => {
-                eprintln!(
-                    "(SERVER)\tUser on {:?} closed before auth",
-                    stream.peer_addr()
-                );
-                return;
+                info!(Net, "User on {:?} closed before auth", stream.peer_addr());
+                metrics::inc(&METRICS.handshake_closed);
+                return None;
             }
             Err(IoError::BadCrypto) => {
-                eprintln!(
-                    "(SERVER)\tUser on {:?} failed crypto on auth",
+                warn!(
+                    Crypto,
+                    "User on {:?} failed crypto on auth",
                     stream.peer_addr()
                 );
-                return;
+                metrics::inc(&METRICS.handshake_bad_crypto);
+                return None;
+            }
+        }
+    }
+}
+
+/// MOTD e aviso de manutenção agendada, logo depois da autenticação.
+async fn greet(db: &Db, stream: &mut Stream, msg: &mut String) -> Result<(), IoError> {
+    if let Some(motd) = db::get_setting(db, "motd")? {
+        for line in motd.lines() {
+            msg.clear();
+            let _ = writeln!(msg, "MOTD {}", line);
+            stream.write_msg(msg).await?;
+        }
+    }
+    let maintenance = db::get_setting(db, "maintenance_at")?.and_then(|at| at.parse().ok());
+    if let (Some(at), Some(text)) = (maintenance, db::get_setting(db, "maintenance_msg")?) {
+        stream
+            .write_msg(&admin::maintenance_notice(at, &text))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Resultado de `send_message`.
+enum Sent {
+    Posted(i64),
+    /// ms até poder mandar de novo
+    SlowMode(i64),
+    Refused(ErrorCode),
+}
+
+/// Grava uma mensagem de sala (ou resposta, com `parent_id`) e a entrega aos membros, com
+/// as menções, numa transação só. Sala e filiação são conferidas de novo lá dentro: a sala
+/// pode ter sido fechada ou o autor removido desde a checagem do comando.
+fn send_message(
+    db: &Db,
+    config: &Config,
+    room: &db::Room,
+    room_name: &str,
+    author: &db::User,
+    parent_id: Option<i64>,
+    sent_msg: &str,
+) -> db::DbResult<Sent> {
+    db::transaction(db, || {
+        match db::Room::get(db, room_name)? {
+            Some(current) if current.id == room.id => {}
+            _ => return Ok(Sent::Refused(ErrorCode::RoomNotFound)),
+        }
+        if !room.is_member(db, author.id)? {
+            return Ok(Sent::Refused(ErrorCode::NotMember));
+        }
+        // antes do modo lento, uma resposta recusada não gasta a vez do usuário
+        if let Some(parent_id) = parent_id {
+            if !room.has_msg(db, parent_id)? {
+                return Ok(Sent::Refused(ErrorCode::MessageNotFound));
+            }
+        }
+        if !room.is_admin(author.id) {
+            if let Err(retry_ms) = room.try_post(db, author.id)? {
+                return Ok(Sent::SlowMode(retry_ms));
+            }
+        }
+        // `head` vai junto com o evento numa só entrega, ver `db::User::drain_msgs`
+        let mut head = String::new();
+        let mut msg = String::new();
+        let msg_id = match parent_id {
+            None => {
+                let msg_id = room.post(db, &author.name, sent_msg)?;
+                // o id vai na linha ID_MENSAGEM logo antes, MENSAGEM continua como os clientes
+                // antigos esperam
+                let _ = writeln!(&mut head, "ID_MENSAGEM {} {}", room_name, msg_id);
+                let _ = writeln!(
+                    &mut msg,
+                    "MENSAGEM {} {} {}",
+                    room_name, author.name, sent_msg
+                );
+                msg_id
+            }
+            Some(parent_id) => {
+                let Some((msg_id, root_id)) = room.reply(db, &author.name, parent_id, sent_msg)?
+                else {
+                    return Ok(Sent::Refused(ErrorCode::MessageNotFound));
+                };
+                let _ = writeln!(
+                    &mut msg,
+                    "RESPOSTA {} {} {} {} {}",
+                    room_name, msg_id, root_id, author.name, sent_msg
+                );
+                msg_id
+            }
+        };
+
+        if let Some(keep) = config.room_history {
+            room.prune_history(db, keep)?;
+        }
+
+        let mut mentioned: Vec<&str> = Vec::new();
+        for name in parse::mentions(sent_msg) {
+            if name != author.name && !mentioned.contains(&name) {
+                mentioned.push(name);
+            }
+        }
+        mentioned.truncate(MAX_MENTIONS);
+
+        let mut except = vec![author.id];
+        for &name in &mentioned {
+            match db::User::get_id(db, name)? {
+                Some(user_id) if room.is_member(db, user_id)? => {
+                    room.add_mention(db, user_id, msg_id)?;
+                    except.push(user_id)
+                }
+                Some(user_id) => {
+                    let notice = format!("MENCAO {} {}\n", room_name, author.name);
+                    db::User::send_to(db, user_id, &notice)?;
+                }
+                None => {}
+            }
+        }
+        room.broadcast_except(db, &format!("{}{}", head, msg), &except)?;
+        if except.len() > 1 {
+            // MENSAGEM ... -> MENSAGEM_MENCAO ...
+            let (event, rest) = msg.split_once(' ').unwrap_or((&msg, ""));
+            let marked = format!("{}{}_MENCAO {}", head, event, rest);
+            for &user_id in &except[1..] {
+                db::User::send_to(db, user_id, &marked)?;
+            }
+        }
+        Ok(Sent::Posted(msg_id))
+    })
+}
+
+/// Escreve cada linha de `lines` como uma mensagem ao cliente.
+async fn write_lines(stream: &mut Stream, lines: &str) -> Result<(), IoError> {
+    for line in lines.lines() {
+        stream.write_msg(line).await?;
+    }
+    Ok(())
+}
+
+async fn handle_client(
+    db: &'static Db,
+    config: &'static Config,
+    mut stream: socket::Stream,
+    rsa_key: RsaKey,
+    pub_key: &'static str,
+) {
+    let mut buf = String::new();
+    let mut msg = String::new();
+    log::enter_conn();
+    info!(Net, "New connection {:?}", stream.peer_addr());
+
+    let ban = match db::GlobalBan::find_ip(db, stream.peer_addr().ip()) {
+        Ok(ban) => ban,
+        Err(err) => {
+            let _ = stream.write_plain_error(db_failure(&err).0).await;
+            return;
+        }
+    };
+    if let Some(ban) = ban {
+        info!(
+            Net,
+            "Rejected banned address {:?} (ban {})",
+            stream.peer_addr(),
+            ban.id
+        );
+        msg.clear();
+        let banned = ErrorCode::ServerBanned.localized(stream.language());
+        let _ = writeln!(&mut msg, "{}: {}", banned, ban.reason);
+        let _ = stream.write_plain_msg(&msg).await;
+        metrics::inc(&METRICS.handshake_banned);
+        return;
+    }
+
+    let peer_addr = stream.peer_addr();
+    let current_user = {
+        let auth = authenticate(db, &mut stream, &rsa_key, pub_key, &mut buf, &mut msg);
+        let mut auth = std::pin::pin!(auth);
+        let started = Instant::now();
+        loop {
+            match async_std::future::timeout(HANDSHAKE_POLL, auth.as_mut()).await {
+                Ok(Some(user)) => break user,
+                Ok(None) => return,
+                // quem ainda não se autenticou não tem o que esperar do encerramento
+                Err(_) if shutdown::remaining().is_some() => return,
+                Err(_) if started.elapsed() >= config.handshake_timeout => {
+                    info!(Net, "Handshake timed out on {:?}", peer_addr);
+                    metrics::inc(&METRICS.handshake_timeout);
+                    return;
+                }
+                Err(_) => {}
             }
         }
     };
 
+    log::set_user(current_user.id);
+    info!(Protocol, "{} authenticated", current_user.name);
+    let addr = stream.peer_addr().ip().to_canonical().to_string();
+    let actor = db::Actor {
+        name: &current_user.name,
+        addr: &addr,
+    };
+
+    let mut rate_limiter = RateLimiter::new(
+        config.rate_burst,
+        config.rate_per_sec,
+        config.rate_max_strikes,
+    );
+
+    // última linha recebida e último PING mandado, para o keepalive; qualquer linha conta como
+    // atividade, então clientes que ignoram PING só precisam mandar algo de vez em quando
+    let mut last_seen = Instant::now();
+    let mut last_ping = Instant::now();
+    let mut ping_token = None;
+
     let mut closed = false;
+    match greet(db, &mut stream, &mut msg).await {
+        Ok(()) => {}
+        Err(IoError::Db(err)) => {
+            let (reply, fatal) = db_failure(&err);
+            closed = stream.write_error(reply).await.is_err() || fatal;
+        }
+        Err(_) => closed = true,
+    }
     'run: while !closed {
-        for new_msg in db::User::drain_msgs(db, current_user.id) {
-            closed |= stream.write_msg(&new_msg).await.is_err();
+        // falha no banco: ERRO e próximo comando, ou desconexão se o erro não for do cliente
+        if db_try!(stream, closed, 'run, current_user.is_kicked(db)) {
+            msg.clear();
+            let kicked = stream.language().text(Text::Kicked);
+            let _ = writeln!(&mut msg, "DESCONECTADO {}", kicked);
+            let _ = stream.write_msg(&msg).await;
+            break 'run;
+        }
+        for new_msg in db_try!(stream, closed, 'run, db::User::drain_msgs(db, current_user.id)) {
+            closed |= write_lines(&mut stream, &new_msg).await.is_err();
             if closed {
                 break 'run;
             }
         }
+        if let Some(secs) = shutdown::remaining() {
+            msg.clear();
+            let _ = writeln!(&mut msg, "SERVIDOR_ENCERRANDO {}", secs);
+            let _ = stream.write_msg(&msg).await;
+            break 'run;
+        }
         match stream.read_line(&mut buf).await {
-            Ok(_) => { /* ok :) */ }
-            Err(IoError::Timeout) => continue,
-            Err(IoError::Failed) => unreachable!(),
+            Ok(_) => last_seen = Instant::now(),
+            Err(IoError::Timeout) => {
+                let idle = last_seen.elapsed();
+                if config.idle_timeout.is_some_and(|timeout| idle >= timeout) {
+                    info!(
+                        Net,
+                        "{} idle for {}s, disconnecting",
+                        current_user.name,
+                        idle.as_secs()
+                    );
+                    msg.clear();
+                    let reason = stream.language().text(Text::Idle);
+                    let _ = writeln!(&mut msg, "DESCONECTADO {}", reason);
+                    let _ = stream.write_msg(&msg).await;
+                    break 'run;
+                }
+                let ping_due = config
+                    .ping_interval
+                    .is_some_and(|interval| idle.min(last_ping.elapsed()) >= interval);
+                if ping_due {
+                    // o cliente devolve o token no PONG, que dá o RTT
+                    let token = db::now_millis();
+                    msg.clear();
+                    let _ = writeln!(&mut msg, "PING {}", token);
+                    closed |= stream.write_msg(&msg).await.is_err();
+                    last_ping = Instant::now();
+                    ping_token = Some(token);
+                }
+                continue;
+            }
+            Err(IoError::Db(err)) => {
+                let (reply, fatal) = db_failure(&err);
+                closed |= stream.write_error(reply).await.is_err() || fatal;
+                continue;
+            }
+            Err(IoError::Failed) => {
+                warn!(Net, "Read from {} failed", current_user.name);
+                break 'run;
+            }
             Err(IoError::BadCrypto) => {
-                eprintln!(
-                    "(SERVER)\tBAD CRYPTO FOR USER {} - {}",
-                    current_user.name, buf
+                warn!(
+                    Crypto,
+                    "Bad crypto from {}: {} bytes",
+                    current_user.name,
+                    buf.trim_end().len()
                 );
                 break 'run;
             }
@@ -190,72 +529,140 @@ async fn handle_client(
             }
         }
 
+        metrics::inc(&METRICS.commands_total);
+        match rate_limiter.check() {
+            Verdict::Allow => {}
+            Verdict::Deny(retry) => {
+                metrics::inc(&METRICS.rate_limited_total);
+                msg.clear();
+                let language = stream.language();
+                let _ = writeln!(
+                    &mut msg,
+                    "{}, {} {:.1}s",
+                    ErrorCode::RateLimited.localized(language),
+                    language.text(Text::RetryIn),
+                    retry.as_secs_f64()
+                );
+                closed |= stream.write_msg(&msg).await.is_err();
+                continue;
+            }
+            Verdict::Disconnect => {
+                warn!(
+                    Protocol,
+                    "User {} disconnected for flooding", current_user.name
+                );
+                let _ = stream.write_error(ErrorCode::Flooding).await;
+                break 'run;
+            }
+        }
+
         match parse::command(&buf) {
-            Some(Command::ListRooms) => {
+            Some(Command::ListRooms { query }) => {
+                let pattern = match query.filter {
+                    None => "%".to_string(),
+                    Some(RoomFilter::Prefix(prefix)) => format!("{}%", db::escape_like(prefix)),
+                    Some(RoomFilter::Contains(text)) => format!("%{}%", db::escape_like(text)),
+                };
+                let limit = query
+                    .limit
+                    .unwrap_or(ROOM_PAGE_DEFAULT)
+                    .clamp(1, ROOM_PAGE_MAX);
+                let Some(page) = db_try!(stream, closed, 'run, db::Room::search(
+                    db,
+                    &pattern,
+                    query.order,
+                    query.after,
+                    limit
+                )) else {
+                    closed |= stream.write_error(ErrorCode::BadCursor).await.is_err();
+                    continue;
+                };
                 msg.clear();
                 let _ = write!(&mut msg, "SALAS");
-                for room_name in db::Room::get_all(db) {
+                for room_name in page.names {
                     let _ = write!(&mut msg, " {}", room_name);
                 }
                 let _ = writeln!(&mut msg);
                 closed |= stream.write_msg(&msg).await.is_err();
+                if let Some(next) = page.next {
+                    msg.clear();
+                    let _ = writeln!(&mut msg, "SALAS_CONTINUA {}", next);
+                    closed |= stream.write_msg(&msg).await.is_err();
+                }
                 if closed {
                     break 'run;
                 }
             }
             Some(Command::LeaveRoom { room_name }) => {
-                let Some(room) = db::Room::get(db, room_name) else {
-                    closed |= stream.write_msg("ERRO sala não encontrada").await.is_err();
+                let Some(room) = db_try!(stream, closed, 'run, db::Room::get(db, room_name)) else {
+                    closed |= stream.write_error(ErrorCode::RoomNotFound).await.is_err();
                     if closed {
                         break 'run;
                     }
                     continue;
                 };
-                if !room.is_member(db, current_user.id) {
-                    closed |= stream.write_msg("ERRO não é membro da sala").await.is_err();
+                if db_try!(stream, closed, 'run, room.dequeue(db, current_user.id)) {
+                    closed |= stream.write_msg("SAIR_SALA_OK").await.is_err();
+                    if closed {
+                        break 'run;
+                    }
+                    continue;
+                }
+                if !db_try!(stream, closed, 'run, room.is_member(db, current_user.id)) {
+                    closed |= stream.write_error(ErrorCode::NotMember).await.is_err();
                     if closed {
                         break 'run;
                     }
                     continue;
                 }
                 if room.is_admin(current_user.id) {
-                    closed |= stream
-                        .write_msg("ERRO admin deve fechar a sala")
-                        .await
-                        .is_err();
+                    closed |= stream.write_error(ErrorCode::AdminMustClose).await.is_err();
                     if closed {
                         break 'run;
                     }
                     continue;
                 }
-                room.kick(db, current_user.id);
                 msg.clear();
                 let _ = writeln!(&mut msg, "SAIU {}", current_user.name);
-                room.broadcast(db, &msg, current_user.id, 0);
+                let left = db::transaction(db, || {
+                    // pode ter saído por outro caminho desde a checagem acima
+                    if !room.kick(db, current_user.id)? {
+                        return Ok(false);
+                    }
+                    room.broadcast(db, &msg, current_user.id, 0)?;
+                    rooms::admit_queued(db, &room, room_name)?;
+                    Ok(true)
+                });
+                if !db_try!(stream, closed, 'run, left) {
+                    closed |= stream.write_error(ErrorCode::NotMember).await.is_err();
+                    continue;
+                }
                 closed |= stream.write_msg("SAIR_SALA_OK").await.is_err();
                 if closed {
                     break 'run;
                 }
             }
             Some(Command::CloseRoom { room_name }) => {
-                let Some(room) = db::Room::get(db, room_name) else {
-                    closed |= stream.write_msg("ERRO sala não encontrada").await.is_err();
+                let Some(room) = db_try!(stream, closed, 'run, db::Room::get(db, room_name)) else {
+                    closed |= stream.write_error(ErrorCode::RoomNotFound).await.is_err();
                     if closed {
                         break 'run;
                     }
                     continue;
                 };
                 if !room.is_admin(current_user.id) {
-                    closed |= stream.write_msg("ERRO não é admin").await.is_err();
+                    closed |= stream.write_error(ErrorCode::NotAdmin).await.is_err();
                     if closed {
                         break 'run;
                     }
                     continue;
                 }
-                msg.clear();
-                let _ = writeln!(&mut msg, "SALA_FECHADA {}", room_name);
-                room.broadcast(db, &msg, current_user.id, 0);
-                room.delete_cascade(db);
+                let closing = db::transaction(db, || {
+                    let audit_room = Some((&room, room_name));
+                    db::AuditEntry::record(db, &actor, "FECHAR_SALA", audit_room, None, "")?;
+                    rooms::close_room(db, &room, room_name, current_user.id)
+                });
+                db_try!(stream, closed, 'run, closing);
                 closed |= stream.write_msg("FECHAR_SALA_OK").await.is_err();
                 if closed {
                     break 'run;
@@ -266,19 +673,37 @@ async fn handle_client(
                 private,
                 pass,
             }) => {
-                if db::Room::get(db, room_name).is_some() {
-                    closed |= stream.write_msg("ERRO sala já existe").await.is_err();
+                if db_try!(stream, closed, 'run, db::Room::get(db, room_name)).is_some() {
+                    closed |= stream.write_error(ErrorCode::RoomExists).await.is_err();
                     continue;
                 }
                 if private && pass.is_empty() {
-                    closed |= stream
-                        .write_msg("ERRO sala privada deve ter uma senha")
-                        .await
-                        .is_err();
+                    closed |= stream.write_error(ErrorCode::PassRequired).await.is_err();
                     continue;
                 }
-                if !db::Room::create(db, room_name, private, pass, current_user.id) {
-                    closed |= stream.write_msg("ERRO sala ja existe? wtf").await.is_err();
+                let persistent = config.persistent_rooms;
+                let pass = db::PassHash::new(pass).await;
+                let created = db::transaction(db, || {
+                    let admin_id = current_user.id;
+                    if !db::Room::create(db, room_name, private, &pass, admin_id, persistent)? {
+                        return Ok(false);
+                    }
+                    if let Some(room) = db::Room::get(db, room_name)? {
+                        let visibility = if private { "PRIVADA" } else { "PUBLICA" };
+                        let audit_room = Some((&room, room_name));
+                        db::AuditEntry::record(
+                            db,
+                            &actor,
+                            "CRIAR_SALA",
+                            audit_room,
+                            None,
+                            visibility,
+                        )?;
+                    }
+                    Ok(true)
+                });
+                if !db_try!(stream, closed, 'run, created) {
+                    closed |= stream.write_error(ErrorCode::RoomExists).await.is_err();
                     continue;
                 }
                 closed |= stream.write_msg("CRIAR_SALA_OK").await.is_err();
@@ -286,128 +711,464 @@ async fn handle_client(
                     break 'run;
                 }
             }
-            Some(Command::JoinRoom { room_name, pass }) => {
-                let Some(room) = db::Room::get(db, room_name) else {
-                    closed |= stream.write_msg("ERRO sala não encontrada").await.is_err();
-                    continue;
+            Some(Command::JoinRoom {
+                room_name,
+                pass,
+                queue,
+            }) => {
+                let join = db::Room::join(db, room_name, current_user.id, pass, queue).await;
+                let room = match db_try!(stream, closed, 'run, join) {
+                    db::Join::Joined(room) => room,
+                    db::Join::Queued(position) => {
+                        msg.clear();
+                        let _ = writeln!(&mut msg, "NA_FILA {} {}", room_name, position);
+                        closed |= stream.write_msg(&msg).await.is_err();
+                        continue;
+                    }
+                    refused => {
+                        let code = match refused {
+                            db::Join::Banned => ErrorCode::RoomBanned,
+                            db::Join::AlreadyMember => ErrorCode::AlreadyMember,
+                            db::Join::WrongPass => ErrorCode::WrongPass,
+                            db::Join::Full => ErrorCode::RoomFull,
+                            _ => ErrorCode::RoomNotFound,
+                        };
+                        closed |= stream.write_error(code).await.is_err();
+                        continue;
+                    }
                 };
-                if room.is_banned(db, current_user.id) {
-                    closed |= stream.write_msg("ERRO banido da sala").await.is_err();
-                    continue;
-                }
-                if room.is_member(db, current_user.id) {
-                    closed |= stream.write_msg("ERRO já está na sala").await.is_err();
-                    continue;
-                }
-                if !room.check_pass(db, pass) {
-                    closed |= stream.write_msg("ERRO senha incorreta").await.is_err();
-                    continue;
-                }
-                room.add_user(db, current_user.id);
 
                 msg.clear();
                 let _ = writeln!(&mut msg, "ENTROU {} {}", room_name, current_user.name);
-                room.broadcast(db, &msg, current_user.id, 0);
+                db_try!(stream, closed, 'run, room.broadcast(db, &msg, current_user.id, 0));
 
                 msg.clear();
                 let _ = write!(&mut msg, "ENTRAR_SALA_OK");
-                for user_name in room.get_users(db) {
+                for user_name in db_try!(stream, closed, 'run, room.get_users(db)) {
                     let _ = write!(&mut msg, " {}", user_name);
                 }
                 let _ = writeln!(&mut msg);
                 closed |= stream.write_msg(&msg).await.is_err();
+
+                let welcome = db_try!(stream, closed, 'run, rooms::welcome(db, &room, room_name));
+                closed |= write_lines(&mut stream, &welcome).await.is_err();
             }
             Some(Command::SendMsg {
                 room_name,
+                parent_id,
                 sent_msg,
             }) => {
-                let Some(room) = db::Room::get(db, room_name) else {
-                    closed |= stream.write_msg("ERRO sala não encontrada").await.is_err();
+                let Some(room) = db_try!(stream, closed, 'run, db::Room::get(db, room_name)) else {
+                    closed |= stream.write_error(ErrorCode::RoomNotFound).await.is_err();
+                    continue;
+                };
+                if !db_try!(stream, closed, 'run, room.is_member(db, current_user.id)) {
+                    closed |= stream.write_error(ErrorCode::NotMember).await.is_err();
+                    continue;
+                }
+                if sent_msg.is_empty() {
+                    closed |= stream.write_error(ErrorCode::EmptyMessage).await.is_err();
+                    continue;
+                }
+                let sent = send_message(
+                    db,
+                    config,
+                    &room,
+                    room_name,
+                    &current_user,
+                    parent_id,
+                    sent_msg,
+                );
+                let msg_id = match db_try!(stream, closed, 'run, sent) {
+                    Sent::Posted(msg_id) => msg_id,
+                    Sent::SlowMode(retry_ms) => {
+                        msg.clear();
+                        let language = stream.language();
+                        let _ = writeln!(
+                            &mut msg,
+                            "{}, {} {:.1}s",
+                            ErrorCode::SlowMode.localized(language),
+                            language.text(Text::RetryIn),
+                            retry_ms as f64 / 1000.0
+                        );
+                        closed |= stream.write_msg(&msg).await.is_err();
+                        continue;
+                    }
+                    Sent::Refused(code) => {
+                        closed |= stream.write_error(code).await.is_err();
+                        continue;
+                    }
+                };
+                metrics::inc(&METRICS.messages_total);
+                msg.clear();
+                // a mesma linha que os outros membros recebem, clientes antigos já a ignoram
+                let _ = writeln!(&mut msg, "ID_MENSAGEM {} {}", room_name, msg_id);
+                closed |= stream.write_msg(&msg).await.is_err();
+            }
+            Some(Command::PinMsg {
+                room_name,
+                msg_id,
+                pinned,
+            }) => {
+                let Some(room) = db_try!(stream, closed, 'run, db::Room::get(db, room_name)) else {
+                    closed |= stream.write_error(ErrorCode::RoomNotFound).await.is_err();
+                    continue;
+                };
+                if !room.is_admin(current_user.id) {
+                    closed |= stream.write_error(ErrorCode::NotAdmin).await.is_err();
+                    continue;
+                }
+                msg.clear();
+                if pinned {
+                    if !db_try!(stream, closed, 'run, room.pin(db, msg_id)) {
+                        closed |= stream.write_error(ErrorCode::NotPinnable).await.is_err();
+                        continue;
+                    }
+                    let _ = writeln!(&mut msg, "MENSAGEM_FIXADA {} {}", room_name, msg_id);
+                } else {
+                    if !db_try!(stream, closed, 'run, room.unpin(db, msg_id)) {
+                        closed |= stream.write_error(ErrorCode::NotPinned).await.is_err();
+                        continue;
+                    }
+                    let _ = writeln!(&mut msg, "MENSAGEM_DESAFIXADA {} {}", room_name, msg_id);
+                }
+                db_try!(stream, closed, 'run, room.broadcast(db, &msg, current_user.id, 0));
+                let reply = if pinned {
+                    "FIXAR_MENSAGEM_OK"
+                } else {
+                    "DESAFIXAR_MENSAGEM_OK"
+                };
+                closed |= stream.write_msg(reply).await.is_err();
+            }
+            Some(Command::ListPins { room_name }) => {
+                let Some(room) = db_try!(stream, closed, 'run, db::Room::get(db, room_name)) else {
+                    closed |= stream.write_error(ErrorCode::RoomNotFound).await.is_err();
+                    continue;
+                };
+                if !db_try!(stream, closed, 'run, room.is_member(db, current_user.id)) {
+                    closed |= stream.write_error(ErrorCode::NotMember).await.is_err();
+                    continue;
+                }
+                let pins = db_try!(stream, closed, 'run, room.get_pins(db));
+                let listing = rooms::pins_listing(room_name, &pins);
+                closed |= write_lines(&mut stream, &listing).await.is_err();
+            }
+            Some(Command::Ping { token }) => {
+                msg.clear();
+                let _ = writeln!(&mut msg, "PONG {}", token.unwrap_or(""));
+                closed |= stream.write_msg(&msg).await.is_err();
+            }
+            Some(Command::Pong { token }) => {
+                let token = token.and_then(|token| token.parse::<i64>().ok());
+                match ping_token {
+                    Some(sent) if token == Some(sent) => {
+                        debug!(Net, "rtt {}ms", db::now_millis() - sent);
+                        ping_token = None;
+                    }
+                    _ => debug!(Net, "ignoring unexpected PONG {:?}", token),
+                }
+            }
+            Some(Command::ListMentions) => {
+                let mentions =
+                    db_try!(stream, closed, 'run, db::User::take_mentions(db, current_user.id));
+                msg.clear();
+                let _ = writeln!(&mut msg, "MENCOES {}", mentions.len());
+                closed |= stream.write_msg(&msg).await.is_err();
+                for (room_name, room_msg) in mentions {
+                    msg.clear();
+                    let _ = writeln!(
+                        &mut msg,
+                        "MENCAO_PENDENTE {} {} {} {}",
+                        room_name, room_msg.id, room_msg.user_name, room_msg.msg
+                    );
+                    closed |= stream.write_msg(&msg).await.is_err();
+                }
+            }
+            Some(Command::ListAudit { room_name }) => {
+                let Some(room) = db_try!(stream, closed, 'run, db::Room::get(db, room_name)) else {
+                    closed |= stream.write_error(ErrorCode::RoomNotFound).await.is_err();
                     continue;
                 };
-                if !room.is_member(db, current_user.id) {
-                    closed |= stream.write_msg("ERRO sala não encontrada").await.is_err();
+                if !room.is_admin(current_user.id) {
+                    closed |= stream.write_error(ErrorCode::NotAdmin).await.is_err();
                     continue;
                 }
+                // o IP de origem fica só para os operadores
+                let entries = db_try!(stream, closed, 'run, db::AuditEntry::get_recent(
+                    db,
+                    Some(room.id),
+                    None,
+                    AUDIT_PAGE
+                ));
+                msg.clear();
+                let _ = writeln!(&mut msg, "AUDITORIA {} {}", room_name, entries.len());
+                closed |= stream.write_msg(&msg).await.is_err();
+                for entry in entries {
+                    msg.clear();
+                    let _ = writeln!(
+                        &mut msg,
+                        "ENTRADA_AUDITORIA {} {} {} {} {} {} {}",
+                        room_name,
+                        entry.id,
+                        entry.at,
+                        entry.actor,
+                        entry.action,
+                        entry.target.as_deref().unwrap_or("-"),
+                        entry.detail
+                    );
+                    closed |= stream.write_msg(&msg).await.is_err();
+                }
+            }
+            Some(Command::ThreadHistory { room_name, msg_id }) => {
+                let Some(room) = db_try!(stream, closed, 'run, db::Room::get(db, room_name)) else {
+                    closed |= stream.write_error(ErrorCode::RoomNotFound).await.is_err();
+                    continue;
+                };
+                if !db_try!(stream, closed, 'run, room.is_member(db, current_user.id)) {
+                    closed |= stream.write_error(ErrorCode::NotMember).await.is_err();
+                    continue;
+                }
+                let thread = db_try!(stream, closed, 'run, room.get_thread(db, msg_id));
+                let Some(root) = thread.first() else {
+                    closed |= stream
+                        .write_error(ErrorCode::MessageNotFound)
+                        .await
+                        .is_err();
+                    continue;
+                };
                 msg.clear();
                 let _ = writeln!(
                     &mut msg,
-                    "MENSAGEM {} {} {}",
-                    room_name, current_user.name, sent_msg
+                    "HISTORICO_THREAD_OK {} {} {}",
+                    room_name, root.id, root.reply_count
                 );
-                room.broadcast(db, &msg, current_user.id, 0);
+                closed |= stream.write_msg(&msg).await.is_err();
+                for room_msg in &thread {
+                    msg.clear();
+                    match room_msg.parent_id {
+                        None => {
+                            let _ = writeln!(&mut msg, "ID_MENSAGEM {} {}", room_name, room_msg.id);
+                            closed |= stream.write_msg(&msg).await.is_err();
+                            msg.clear();
+                            let _ = writeln!(
+                                &mut msg,
+                                "MENSAGEM {} {} {}",
+                                room_name, room_msg.user_name, room_msg.msg
+                            );
+                        }
+                        Some(root_id) => {
+                            let _ = writeln!(
+                                &mut msg,
+                                "RESPOSTA {} {} {} {} {}",
+                                room_name, room_msg.id, root_id, room_msg.user_name, room_msg.msg
+                            );
+                        }
+                    }
+                    closed |= stream.write_msg(&msg).await.is_err();
+                }
             }
             Some(Command::BanUser {
                 room_name,
                 banned_name,
             }) => {
-                let Some(room) = db::Room::get(db, room_name) else {
-                    closed |= stream.write_msg("ERRO sala não encontrada").await.is_err();
+                let Some(room) = db_try!(stream, closed, 'run, db::Room::get(db, room_name)) else {
+                    closed |= stream.write_error(ErrorCode::RoomNotFound).await.is_err();
                     continue;
                 };
                 if room.admin != current_user.id {
-                    closed |= stream.write_msg("ERRO não é admin").await.is_err();
+                    closed |= stream.write_error(ErrorCode::NotAdmin).await.is_err();
                     continue;
                 }
-                let Some(banned_id) = db::User::get_id(db, banned_name) else {
-                    closed |= stream
-                        .write_msg("ERRO usuário não encontrado")
-                        .await
-                        .is_err();
+                let Some(banned_id) =
+                    db_try!(stream, closed, 'run, db::User::get_id(db, banned_name))
+                else {
+                    closed |= stream.write_error(ErrorCode::UserNotFound).await.is_err();
                     continue;
                 };
                 if banned_id == current_user.id {
-                    closed |= stream
-                        .write_msg("ERRO não pode banir a si mesmo")
-                        .await
-                        .is_err();
+                    closed |= stream.write_error(ErrorCode::SelfBan).await.is_err();
                     continue;
                 }
-                if room.kick(db, banned_id) {
-                    msg.clear();
-                    let _ = writeln!(&mut msg, "SAIU {} {}", room_name, banned_name);
-                    room.broadcast(db, &msg, current_user.id, banned_id);
-                }
-                if room.ban(db, banned_id) {
-                    msg.clear();
-                    let _ = writeln!(&mut msg, "BANIDO_DA_SALA {}", room_name);
-                    db::User::send_to(db, banned_id, &msg);
-                }
+                let banning = db::transaction(db, || {
+                    let actor_id = current_user.id;
+                    rooms::ban_user(db, &room, room_name, banned_id, banned_name, actor_id)?;
+                    let audit_room = Some((&room, room_name));
+                    let target = Some(banned_name);
+                    db::AuditEntry::record(db, &actor, "BANIR_USUARIO", audit_room, target, "")
+                });
+                db_try!(stream, closed, 'run, banning);
                 msg.clear();
                 let _ = writeln!(&mut msg, "BANIMENTO_OK {}", banned_name);
                 closed |= stream.write_msg(&msg).await.is_err();
             }
+            Some(Command::EditRoom { room_name, setting }) => {
+                let Some(room) = db_try!(stream, closed, 'run, db::Room::get(db, room_name)) else {
+                    closed |= stream.write_error(ErrorCode::RoomNotFound).await.is_err();
+                    continue;
+                };
+                if !room.is_admin(current_user.id) {
+                    closed |= stream.write_error(ErrorCode::NotAdmin).await.is_err();
+                    continue;
+                }
+                msg.clear();
+                match setting {
+                    RoomSetting::Pass(pass) => {
+                        let pass = db::PassHash::new(pass).await;
+                        let changed = db::transaction(db, || {
+                            if !room.set_pass(db, &pass)? {
+                                return Ok(false);
+                            }
+                            let audit_room = Some((&room, room_name));
+                            db::AuditEntry::record(
+                                db,
+                                &actor,
+                                "ALTERAR_SALA",
+                                audit_room,
+                                None,
+                                "SENHA",
+                            )?;
+                            Ok(true)
+                        });
+                        if !db_try!(stream, closed, 'run, changed) {
+                            closed |= stream.write_error(ErrorCode::PassRequired).await.is_err();
+                            continue;
+                        }
+                    }
+                    RoomSetting::Private(private) => {
+                        if !db_try!(stream, closed, 'run, room.set_private(db, private)) {
+                            closed |= stream.write_error(ErrorCode::PassRequired).await.is_err();
+                            continue;
+                        }
+                        let visibility = if private { "PRIVADA" } else { "PUBLICA" };
+                        let _ = writeln!(
+                            &mut msg,
+                            "SALA_ALTERADA {} VISIBILIDADE {}",
+                            room_name, visibility
+                        );
+                    }
+                    RoomSetting::Name(new_name) => {
+                        if !db_try!(stream, closed, 'run, room.rename(db, new_name)) {
+                            closed |= stream.write_error(ErrorCode::RoomExists).await.is_err();
+                            continue;
+                        }
+                        let _ = writeln!(&mut msg, "SALA_ALTERADA {} NOME {}", room_name, new_name);
+                    }
+                    RoomSetting::Topic(topic) => {
+                        db_try!(stream, closed, 'run, room.set_topic(db, topic));
+                        let _ = writeln!(&mut msg, "SALA_ALTERADA {} TOPICO {}", room_name, topic);
+                    }
+                    RoomSetting::MaxMembers(max_members) => {
+                        db_try!(stream, closed, 'run, room.set_max_members(db, max_members));
+                        let _ = writeln!(
+                            &mut msg,
+                            "SALA_ALTERADA {} LIMITE {}",
+                            room_name, max_members
+                        );
+                    }
+                    RoomSetting::SlowMode(secs) => {
+                        db_try!(stream, closed, 'run, room.set_slow_mode(db, secs));
+                        let _ = writeln!(&mut msg, "SALA_ALTERADA {} LENTO {}", room_name, secs);
+                    }
+                }
+                // troca de senha não é visível aos membros
+                if !msg.is_empty() {
+                    db_try!(stream, closed, 'run, room.broadcast(db, &msg, current_user.id, 0));
+                }
+                if let RoomSetting::MaxMembers(_) = setting {
+                    db_try!(stream, closed, 'run, rooms::admit_queued(db, &room, room_name));
+                }
+                closed |= stream.write_msg("ALTERAR_SALA_OK").await.is_err();
+            }
             None => {
-                closed |= stream
-                    .write_msg("ERRO comando nao reconhecido")
-                    .await
-                    .is_err();
+                closed |= stream.write_error(ErrorCode::UnknownCommand).await.is_err();
                 if closed {
                     break 'run;
                 }
             }
         }
     }
-    for (joined_room, name) in db::Room::get_all_from_member(db, current_user.id) {
+    if let Err(err) = disconnect(db, &current_user, &actor) {
+        error!(Db, "cleanup after {} failed: {}", current_user.name, err);
+        // sem isso o nome ficaria ocupado
+        let _ = current_user.delete_cascade(db);
+    }
+}
+
+/// Avisa as salas da saída do usuário, fecha as salas dele e o remove.
+fn disconnect(db: &Db, current_user: &db::User, actor: &db::Actor) -> db::DbResult<()> {
+    db::transaction(db, || leave_all(db, current_user, actor))
+}
+
+fn leave_all(db: &Db, current_user: &db::User, actor: &db::Actor) -> db::DbResult<()> {
+    let mut msg = String::new();
+    let joined_rooms = db::Room::get_all_from_member(db, current_user.id)?;
+    for (joined_room, name) in &joined_rooms {
         msg.clear();
         let _ = writeln!(&mut msg, "SAIU {} {}", name, current_user.name);
-        joined_room.broadcast(db, &msg, current_user.id, 0);
+        joined_room.broadcast(db, &msg, current_user.id, 0)?;
     }
-    for (owned_room, name) in db::Room::get_all_from_admin(db, current_user.id) {
+    for (owned_room, name) in db::Room::get_all_from_admin(db, current_user.id)? {
+        let audit_room = Some((&owned_room, name.as_str()));
+        db::AuditEntry::record(db, actor, "FECHAR_SALA", audit_room, None, "DESCONECTOU")?;
         msg.clear();
         let _ = writeln!(&mut msg, "SALA_FECHADA {}", name);
-        owned_room.broadcast(db, &msg, current_user.id, 0);
+        owned_room.broadcast(db, &msg, current_user.id, 0)?;
     }
-    current_user.delete_cascade(db);
+    current_user.delete_cascade(db)?;
+    for (joined_room, name) in &joined_rooms {
+        rooms::admit_queued(db, joined_room, name)?;
+    }
+    Ok(())
+}
+
+/// Fecha salas persistentes ociosas além de `config.room_expiry`.
+async fn expire_rooms(db: &'static Db, config: &'static Config) {
+    let Some(room_expiry) = config.room_expiry else {
+        return;
+    };
+    loop {
+        task::sleep(room_expiry.min(Duration::from_secs(60))).await;
+        if let Err(err) = expire_idle(db, room_expiry.as_secs() as i64) {
+            error!(Server, "room expiry failed: {}", err);
+        }
+    }
+}
+
+fn expire_idle(db: &Db, idle_secs: i64) -> db::DbResult<()> {
+    for (room, name) in db::Room::get_expired(db, idle_secs)? {
+        info!(Server, "room {} expired", name);
+        db::transaction(db, || {
+            let audit_room = Some((&room, name.as_str()));
+            db::AuditEntry::record(db, &db::Actor::SERVER, "expire", audit_room, None, "")?;
+            rooms::close_room(db, &room, &name, 0)
+        })?;
+    }
+    Ok(())
+}
+
+/// `ERRO SERVIDOR_CHEIO` em texto puro, antes de qualquer handshake.
+async fn refuse(mut stream: TcpStream) {
+    let msg = format!("{}\n", ErrorCode::ServerFull.localized(i18n::default()));
+    let _ = stream.write_all(msg.as_bytes()).await;
 }
 
 #[async_std::main]
 async fn main() {
-    let db = Box::leak(Box::new(
+    let db: &'static Db = Box::leak(Box::new(
         sqlite::Connection::open_thread_safe(":memory:").unwrap(),
     ));
     db.execute(include_str!("./create.sql")).unwrap();
     db.execute(include_str!("./populate.sql")).unwrap();
+    let config: &'static Config = Box::leak(Box::new(Config::from_env()));
+    if let Err(err) = log::configure(&config.log) {
+        warn!(Server, "ignoring invalid CHAT_LOG: {}", err);
+    }
+    log::set_json(config.log_json);
+    log::set_payloads(config.log_payloads);
+    i18n::load(config.lang_dir.as_deref());
+    if let Some(motd) = &config.motd {
+        db::set_setting(db, "motd", Some(motd)).unwrap();
+    }
 
     let rsa_key = rsa::Rsa::generate(1024).unwrap();
     let pub_key = rsa_key.public_key_to_der().unwrap();
@@ -417,19 +1178,81 @@ async fn main() {
         .nth(1)
         .and_then(|addr| SocketAddr::from_str(&addr).ok())
         .unwrap_or(SocketAddr::from(([0, 0, 0, 0], 8080)));
-    eprintln!("(SERVER)\tlistening on {}", addr);
+    info!(Server, "listening on {}", addr);
 
-    task::spawn(admin(db));
+    task::spawn(admin::console(db));
+    task::spawn(admin::serve(db, config));
+    task::spawn(expire_rooms(db, config));
+    task::spawn(metrics::serve(db, config.metrics_addr));
     let listener = TcpListener::bind(addr)
         .await
         .unwrap_or_else(|_| panic!("Cannot listen on addr {}", addr));
 
-    while let Some(stream) = listener.incoming().next().await {
-        let Ok(stream) = stream else { continue };
-        let stream = Stream::new(stream);
-        let db = &*db;
-        let rsa_key = rsa_key.clone();
-        let pub_key = &*pub_key;
-        task::spawn(handle_client(db, stream, rsa_key, pub_key));
+    shutdown::install();
+    let limits: &'static ConnLimits = Box::leak(Box::new(ConnLimits::new(config)));
+    let accept = task::spawn(async move {
+        while let Some(stream) = listener.incoming().next().await {
+            let Ok(stream) = stream else { continue };
+            let Ok(peer_addr) = stream.peer_addr() else {
+                continue;
+            };
+            metrics::inc(&METRICS.connections_total);
+            let guard = match limits.admit(peer_addr.ip()) {
+                Ok(guard) => guard,
+                Err(reject) => {
+                    info!(Net, "Refused connection {:?}: {:?}", peer_addr, reject);
+                    metrics::inc(match reject {
+                        Reject::Full => &METRICS.rejected_full,
+                        Reject::PerIp => &METRICS.rejected_per_ip,
+                        Reject::Rate => &METRICS.rejected_rate,
+                    });
+                    task::spawn(refuse(stream));
+                    continue;
+                }
+            };
+            let stream = Stream::new(stream, peer_addr);
+            let rsa_key = rsa_key.clone();
+            let pub_key = &*pub_key;
+            task::spawn(async move {
+                handle_client(db, config, stream, rsa_key, pub_key).await;
+                drop(guard);
+            });
+        }
+    });
+
+    shutdown::requested().await;
+    info!(
+        Server,
+        "shutting down, waiting up to {}s for clients",
+        config.shutdown_deadline.as_secs()
+    );
+    // derruba o listener, nenhuma conexão nova
+    accept.cancel().await;
+    shutdown::begin(config.shutdown_deadline);
+    while METRICS.connections_open.load(Ordering::SeqCst) > 0 {
+        if shutdown::remaining() == Some(0) {
+            warn!(Server, "deadline reached, dropping remaining connections");
+            break;
+        }
+        task::sleep(Duration::from_millis(100)).await;
+    }
+
+    if let Some(path) = &config.dump_file {
+        // VACUUM INTO não sobrescreve; a cópia anterior só é trocada se a nova deu certo
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = std::path::PathBuf::from(tmp);
+        let _ = std::fs::remove_file(&tmp);
+        let saved = db::dump(db, &tmp)
+            .map_err(|err| err.to_string())
+            .and_then(|()| std::fs::rename(&tmp, path).map_err(|err| err.to_string()));
+        match saved {
+            Ok(()) => info!(Server, "database saved to {:?}", path),
+            Err(err) => error!(Server, "cannot save database to {:?}: {}", path, err),
+        }
+    }
+    if let Some(path) = &config.admin_socket {
+        let _ = admin::remove_socket(path);
     }
+    info!(Server, "bye");
 }
@@ -0,0 +1,286 @@
+//! Métricas no formato texto do Prometheus, servidas por HTTP em `CHAT_METRICS_ADDR`.
+use async_std::io::BufReader;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use sqlite::{ConnectionThreadSafe as Db, Statement};
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::db;
+
+/// Limites dos baldes do histograma de latência das queries, em segundos.
+const QUERY_BUCKETS: [f64; 8] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5];
+
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    pub connections_open: AtomicI64,
+    pub connections_total: AtomicU64,
+    pub handshake_closed: AtomicU64,
+    pub handshake_bad_crypto: AtomicU64,
+    pub handshake_rejected: AtomicU64,
+    pub handshake_banned: AtomicU64,
+    pub handshake_timeout: AtomicU64,
+    pub rejected_full: AtomicU64,
+    pub rejected_per_ip: AtomicU64,
+    pub rejected_rate: AtomicU64,
+    pub commands_total: AtomicU64,
+    pub rate_limited_total: AtomicU64,
+    pub messages_total: AtomicU64,
+    query_buckets: [AtomicU64; QUERY_BUCKETS.len()],
+    query_count: AtomicU64,
+    query_micros: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            connections_open: AtomicI64::new(0),
+            connections_total: ZERO,
+            handshake_closed: ZERO,
+            handshake_bad_crypto: ZERO,
+            handshake_rejected: ZERO,
+            handshake_banned: ZERO,
+            handshake_timeout: ZERO,
+            rejected_full: ZERO,
+            rejected_per_ip: ZERO,
+            rejected_rate: ZERO,
+            commands_total: ZERO,
+            rate_limited_total: ZERO,
+            messages_total: ZERO,
+            query_buckets: [ZERO; QUERY_BUCKETS.len()],
+            query_count: ZERO,
+            query_micros: ZERO,
+        }
+    }
+
+    fn observe_query(&self, secs: f64) {
+        for (bucket, &le) in self.query_buckets.iter().zip(&QUERY_BUCKETS) {
+            if secs <= le {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.query_count.fetch_add(1, Ordering::Relaxed);
+        self.query_micros
+            .fetch_add((secs * 1e6) as u64, Ordering::Relaxed);
+    }
+}
+
+/// Soma um ao contador.
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Statement que, ao ser descartado, registra o tempo desde o `prepare` no histograma.
+pub struct TimedStatement<'l> {
+    statement: Statement<'l>,
+    start: Instant,
+}
+
+impl<'l> TimedStatement<'l> {
+    pub fn new(statement: Statement<'l>, start: Instant) -> Self {
+        Self { statement, start }
+    }
+}
+
+impl<'l> Deref for TimedStatement<'l> {
+    type Target = Statement<'l>;
+
+    fn deref(&self) -> &Statement<'l> {
+        &self.statement
+    }
+}
+
+impl DerefMut for TimedStatement<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.statement
+    }
+}
+
+impl Drop for TimedStatement<'_> {
+    fn drop(&mut self) {
+        METRICS.observe_query(self.start.elapsed().as_secs_f64());
+    }
+}
+
+/// Serve `GET /metrics`, desligado se `CHAT_METRICS_ADDR` não estiver definido.
+pub async fn serve(db: &'static Db, addr: Option<SocketAddr>) {
+    let Some(addr) = addr else {
+        return;
+    };
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!(Server, "cannot bind metrics on {}: {}", addr, err);
+            return;
+        }
+    };
+    if !addr.ip().is_loopback() {
+        warn!(Server, "metrics exposed on non-loopback {}", addr);
+    }
+    info!(Server, "metrics on http://{}/metrics", addr);
+
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let Ok(stream) = stream else { continue };
+        task::spawn(respond(db, stream));
+    }
+}
+
+async fn respond(db: &'static Db, stream: TcpStream) {
+    let mut reader = BufReader::new(stream.clone());
+    let mut writer = stream;
+    let mut request = String::new();
+    if reader.read_line(&mut request).await.is_err() {
+        return;
+    }
+    // descarta os cabeçalhos
+    let mut header = String::new();
+    loop {
+        header.clear();
+        match reader.read_line(&mut header).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) if header.trim().is_empty() => break,
+            Ok(_) => {}
+        }
+    }
+
+    let mut split = request.split_whitespace();
+    let (status, body) = match (split.next(), split.next()) {
+        (Some("GET"), Some("/metrics")) => match render(db) {
+            Ok(body) => ("200 OK", body),
+            Err(err) => {
+                error!(Db, "cannot render metrics: {}", err);
+                ("500 Internal Server Error", "database error\n".to_string())
+            }
+        },
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = writer.write_all(response.as_bytes()).await;
+}
+
+fn render(db: &'static Db) -> db::DbResult<String> {
+    let m = &METRICS;
+    let load = |value: &AtomicU64| value.load(Ordering::Relaxed) as i64;
+    let gauges = db::Gauges::get(db)?;
+    let mut out = String::new();
+
+    let open = m.connections_open.load(Ordering::Relaxed);
+    let accepted = load(&m.connections_total);
+    for (name, kind, help, value) in [
+        ("connections_open", "gauge", "TCP connections open", open),
+        (
+            "connections_total",
+            "counter",
+            "TCP connections accepted",
+            accepted,
+        ),
+        ("users", "gauge", "authenticated users", gauges.users),
+        ("rooms", "gauge", "open rooms", gauges.rooms),
+        (
+            "pending_deliveries",
+            "gauge",
+            "queued in rel_user_msg",
+            gauges.pending_deliveries,
+        ),
+        (
+            "commands_total",
+            "counter",
+            "commands received",
+            load(&m.commands_total),
+        ),
+        (
+            "rate_limited_total",
+            "counter",
+            "commands rate limited",
+            load(&m.rate_limited_total),
+        ),
+        (
+            "messages_total",
+            "counter",
+            "room messages posted",
+            load(&m.messages_total),
+        ),
+    ] {
+        header(&mut out, name, kind, help);
+        let _ = writeln!(out, "chat_{} {}", name, value);
+    }
+
+    header(
+        &mut out,
+        "handshake_failures_total",
+        "counter",
+        "failures before auth",
+    );
+    for (kind, value) in [
+        ("closed", &m.handshake_closed),
+        ("bad_crypto", &m.handshake_bad_crypto),
+        ("rejected", &m.handshake_rejected),
+        ("banned", &m.handshake_banned),
+        ("timeout", &m.handshake_timeout),
+    ] {
+        let value = load(value);
+        let _ = writeln!(
+            out,
+            "chat_handshake_failures_total{{kind=\"{}\"}} {}",
+            kind, value
+        );
+    }
+
+    header(
+        &mut out,
+        "connections_rejected_total",
+        "counter",
+        "connections refused by limits",
+    );
+    for (reason, value) in [
+        ("full", &m.rejected_full),
+        ("per_ip", &m.rejected_per_ip),
+        ("rate", &m.rejected_rate),
+    ] {
+        let value = load(value);
+        let _ = writeln!(
+            out,
+            "chat_connections_rejected_total{{reason=\"{}\"}} {}",
+            reason, value
+        );
+    }
+
+    header(
+        &mut out,
+        "db_query_seconds",
+        "histogram",
+        "SQLite prepare to drop",
+    );
+    for (bucket, le) in m.query_buckets.iter().zip(QUERY_BUCKETS) {
+        let value = load(bucket);
+        let _ = writeln!(
+            out,
+            "chat_db_query_seconds_bucket{{le=\"{}\"}} {}",
+            le, value
+        );
+    }
+    let count = load(&m.query_count);
+    let sum = load(&m.query_micros) as f64 / 1e6;
+    let _ = writeln!(out, "chat_db_query_seconds_bucket{{le=\"+Inf\"}} {}", count);
+    let _ = writeln!(out, "chat_db_query_seconds_sum {}", sum);
+    let _ = writeln!(out, "chat_db_query_seconds_count {}", count);
+    Ok(out)
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP chat_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE chat_{} {}", name, kind);
+}
@@ -1,43 +1,90 @@
-pub fn command_register<'a>(line: &'a str) -> Option<&'a str> {
+//! Comandos do cliente. Cada palavra-chave em português também é aceita em inglês
+//! (`LISTAR_SALAS` ou `LIST_ROOMS`, `ORDEM` ou `ORDER`...); as respostas não mudam.
+use crate::db::RoomOrder;
+
+/// `REGISTRO usuario [idioma]`
+#[allow(clippy::needless_lifetimes)]
+pub fn command_register<'a>(line: &'a str) -> Option<(&'a str, Option<&'a str>)> {
     let mut split = line.split_whitespace();
-    match (split.next(), split.next()) {
-        (Some("REGISTRO"), Some(username)) => Some(username),
+    match (split.next(), split.next(), split.next(), split.next()) {
+        (Some("REGISTRO" | "REGISTER"), Some(username), language, None) => {
+            Some((username, language))
+        }
         _ => None,
     }
 }
 
+#[allow(clippy::needless_lifetimes)]
 pub fn command_auth<'a>(line: &'a str) -> Option<&'a str> {
     let mut split = line.split_whitespace();
     match (split.next(), split.next()) {
-        (Some("AUTENTICACAO"), Some(username)) => Some(username),
+        (Some("AUTENTICACAO" | "AUTHENTICATE"), Some(username)) => Some(username),
         _ => None,
     }
 }
 
+#[allow(clippy::needless_lifetimes)]
 pub fn command_aes_key<'a>(line: &'a str) -> Option<&'a str> {
     let mut split = line.split_whitespace();
     match (split.next(), split.next()) {
-        (Some("CHAVE_SIMETRICA"), Some(key)) => Some(key),
+        (Some("CHAVE_SIMETRICA" | "SYMMETRIC_KEY"), Some(key)) => Some(key),
         _ => None,
     }
 }
 
+/// Nomes mencionados como `@nome` no texto, sem pontuação final.
+pub fn mentions(text: &str) -> impl Iterator<Item = &str> {
+    text.split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| name.trim_end_matches(|c: char| c.is_ascii_punctuation()))
+        .filter(|name| !name.is_empty())
+}
+
 pub fn command<'a>(line: &'a str) -> Option<Command<'a>> {
     let mut split = line.split_whitespace();
     match split.next() {
-        Some("LISTAR_SALAS") => Some(Command::ListRooms),
-        Some("SAIR_SALA") => {
+        Some("LISTAR_SALAS" | "LIST_ROOMS") => {
+            let mut query = RoomQuery {
+                filter: None,
+                order: RoomOrder::Name,
+                limit: None,
+                after: None,
+            };
+            while let Some(option) = split.next() {
+                match option {
+                    "PREFIXO" | "PREFIX" => query.filter = Some(RoomFilter::Prefix(split.next()?)),
+                    "CONTEM" | "CONTAINS" => {
+                        query.filter = Some(RoomFilter::Contains(split.next()?))
+                    }
+                    "ORDEM" | "ORDER" => {
+                        query.order = match split.next()? {
+                            "NOME" | "NAME" => RoomOrder::Name,
+                            "MEMBROS" | "MEMBERS" => RoomOrder::Members,
+                            "ATIVIDADE" | "ACTIVITY" => RoomOrder::Activity,
+                            _ => return None,
+                        }
+                    }
+                    "LIMITE" | "LIMIT" => query.limit = Some(split.next()?.parse().ok()?),
+                    "APOS" | "AFTER" => query.after = Some(split.next()?),
+                    // o LISTAR_SALAS original ignorava o resto da linha, e continua ignorando
+                    // o que não conhece
+                    _ => {}
+                }
+            }
+            Some(Command::ListRooms { query })
+        }
+        Some("SAIR_SALA" | "LEAVE_ROOM") => {
             let room_name = split.next()?;
             Some(Command::LeaveRoom { room_name })
         }
-        Some("FECHAR_SALA") => {
+        Some("FECHAR_SALA" | "CLOSE_ROOM") => {
             let room_name = split.next()?;
             Some(Command::CloseRoom { room_name })
         }
-        Some("CRIAR_SALA") => {
+        Some("CRIAR_SALA" | "CREATE_ROOM") => {
             let private = match split.next() {
-                Some("PUBLICA") => false,
-                Some("PRIVADA") => true,
+                Some("PUBLICA" | "PUBLIC") => false,
+                Some("PRIVADA" | "PRIVATE") => true,
                 _ => return None,
             };
             let room_name = split.next()?;
@@ -48,20 +95,64 @@ pub fn command<'a>(line: &'a str) -> Option<Command<'a>> {
                 pass,
             })
         }
-        Some("ENTRAR_SALA") => {
+        Some(cmd @ ("ENTRAR_SALA" | "ENTRAR_SALA_FILA" | "JOIN_ROOM" | "JOIN_ROOM_QUEUE")) => {
             let room_name = split.next()?;
             let pass = split.next().unwrap_or("");
-            Some(Command::JoinRoom { room_name, pass })
+            Some(Command::JoinRoom {
+                room_name,
+                pass,
+                queue: matches!(cmd, "ENTRAR_SALA_FILA" | "JOIN_ROOM_QUEUE"),
+            })
         }
-        Some("ENVIAR_MENSAGEM") => {
+        Some("ENVIAR_MENSAGEM" | "SEND_MESSAGE") => {
             let room_name = split.next()?;
             let sent_msg = split.remainder().unwrap_or("").trim();
             Some(Command::SendMsg {
                 room_name,
+                parent_id: None,
                 sent_msg,
             })
         }
-        Some("BANIR_USUARIO") => {
+        Some("RESPONDER" | "REPLY") => {
+            let room_name = split.next()?;
+            let parent_id = split.next()?.parse().ok()?;
+            let sent_msg = split.remainder().unwrap_or("").trim();
+            Some(Command::SendMsg {
+                room_name,
+                parent_id: Some(parent_id),
+                sent_msg,
+            })
+        }
+        Some(cmd @ ("FIXAR_MENSAGEM" | "DESAFIXAR_MENSAGEM" | "PIN_MESSAGE" | "UNPIN_MESSAGE")) => {
+            let room_name = split.next()?;
+            let msg_id = split.next()?.parse().ok()?;
+            Some(Command::PinMsg {
+                room_name,
+                msg_id,
+                pinned: matches!(cmd, "FIXAR_MENSAGEM" | "PIN_MESSAGE"),
+            })
+        }
+        Some("LISTAR_FIXADAS" | "LIST_PINS") => {
+            let room_name = split.next()?;
+            Some(Command::ListPins { room_name })
+        }
+        Some("LISTAR_MENCOES" | "LIST_MENTIONS") => Some(Command::ListMentions),
+        Some("PING") => Some(Command::Ping {
+            token: split.next(),
+        }),
+        Some("PONG") => Some(Command::Pong {
+            token: split.next(),
+        }),
+        Some("LISTAR_AUDITORIA" | "LIST_AUDIT") => {
+            let room_name = split.next()?;
+            Some(Command::ListAudit { room_name })
+        }
+        Some("HISTORICO_THREAD" | "THREAD_HISTORY") => {
+            let room_name = split.next()?;
+            let msg_id = split.next()?.parse().ok()?;
+            Some(Command::ThreadHistory { room_name, msg_id })
+        }
+        Some("BANIR_USUARIO" | "BAN_USER") => {
             let room_name = split.next()?;
             let banned_name = split.next()?;
             Some(Command::BanUser {
@@ -69,13 +160,60 @@ pub fn command<'a>(line: &'a str) -> Option<Command<'a>> {
                 banned_name,
             })
         }
+        Some("ALTERAR_SALA" | "EDIT_ROOM") => {
+            let room_name = split.next()?;
+            let setting = match split.next()? {
+                "SENHA" | "PASSWORD" => RoomSetting::Pass(split.remainder().unwrap_or("").trim()),
+                "VISIBILIDADE" | "VISIBILITY" => match split.next()? {
+                    "PUBLICA" | "PUBLIC" => RoomSetting::Private(false),
+                    "PRIVADA" | "PRIVATE" => RoomSetting::Private(true),
+                    _ => return None,
+                },
+                "NOME" | "NAME" => RoomSetting::Name(split.next()?),
+                "TOPICO" | "TOPIC" => RoomSetting::Topic(split.remainder().unwrap_or("").trim()),
+                "LIMITE" | "LIMIT" => RoomSetting::MaxMembers(split.next()?.parse().ok()?),
+                "LENTO" | "SLOW" => RoomSetting::SlowMode(split.next()?.parse().ok()?),
+                _ => return None,
+            };
+            Some(Command::EditRoom { room_name, setting })
+        }
         _ => None,
     }
 }
 
+#[derive(Debug)]
+pub enum RoomFilter<'a> {
+    Prefix(&'a str),
+    Contains(&'a str),
+}
+
+/// `LISTAR_SALAS [PREFIXO|CONTEM texto] [ORDEM NOME|MEMBROS|ATIVIDADE] [LIMITE n] [APOS token]`
+#[derive(Debug)]
+pub struct RoomQuery<'a> {
+    pub filter: Option<RoomFilter<'a>>,
+    pub order: RoomOrder,
+    pub limit: Option<u32>,
+    /// token de continuação da página anterior
+    pub after: Option<&'a str>,
+}
+
+#[derive(Debug)]
+pub enum RoomSetting<'a> {
+    Pass(&'a str),
+    Private(bool),
+    Name(&'a str),
+    Topic(&'a str),
+    /// 0 = sem limite
+    MaxMembers(u32),
+    /// segundos entre mensagens de cada membro, 0 = desligado
+    SlowMode(u32),
+}
+
 #[derive(Debug)]
 pub enum Command<'a> {
-    ListRooms,
+    ListRooms {
+        query: RoomQuery<'a>,
+    },
     LeaveRoom {
         room_name: &'a str,
     },
@@ -90,13 +228,46 @@ pub enum Command<'a> {
     JoinRoom {
         room_name: &'a str,
         pass: &'a str,
+        /// entra na fila de espera se a sala estiver cheia
+        queue: bool,
     },
     SendMsg {
         room_name: &'a str,
+        /// `RESPONDER` a uma mensagem da sala
+        parent_id: Option<i64>,
         sent_msg: &'a str,
     },
+    ThreadHistory {
+        room_name: &'a str,
+        msg_id: i64,
+    },
+    PinMsg {
+        room_name: &'a str,
+        msg_id: i64,
+        /// `false` para `DESAFIXAR_MENSAGEM`
+        pinned: bool,
+    },
+    ListPins {
+        room_name: &'a str,
+    },
+    ListMentions,
+    /// respondido com `PONG` e o mesmo token, para o cliente medir o RTT
+    Ping {
+        token: Option<&'a str>,
+    },
+    /// resposta a um `PING` do servidor
+    Pong {
+        token: Option<&'a str>,
+    },
+    ListAudit {
+        room_name: &'a str,
+    },
     BanUser {
         room_name: &'a str,
         banned_name: &'a str,
     },
+    EditRoom {
+        room_name: &'a str,
+        setting: RoomSetting<'a>,
+    },
 }
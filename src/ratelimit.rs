@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+/// Janela em que violações seguidas contam como abuso sustentado.
+const STRIKE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Token bucket por conexão, mais contagem de violações para desconectar abusos.
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    last: Instant,
+    max_strikes: u32,
+    strikes: u32,
+    strikes_since: Instant,
+}
+
+pub enum Verdict {
+    Allow,
+    /// tempo até haver um token disponível
+    Deny(Duration),
+    /// abuso sustentado, a conexão deve ser encerrada
+    Disconnect,
+}
+
+impl RateLimiter {
+    pub fn new(burst: u32, refill_per_sec: f64, max_strikes: u32) -> Self {
+        let now = Instant::now();
+        Self {
+            capacity: burst as f64,
+            refill_per_sec,
+            tokens: burst as f64,
+            last: now,
+            max_strikes,
+            strikes: 0,
+            strikes_since: now,
+        }
+    }
+
+    pub fn check(&mut self) -> Verdict {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Verdict::Allow;
+        }
+
+        if now.duration_since(self.strikes_since) > STRIKE_WINDOW {
+            self.strikes = 0;
+            self.strikes_since = now;
+        }
+        self.strikes += 1;
+        if self.strikes > self.max_strikes {
+            return Verdict::Disconnect;
+        }
+        let missing = 1.0 - self.tokens;
+        Verdict::Deny(Duration::from_secs_f64(missing / self.refill_per_sec))
+    }
+}
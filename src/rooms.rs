@@ -0,0 +1,106 @@
+//! Operações sobre salas que avisam os envolvidos, usadas pelos comandos dos clientes e pelo
+//! console de administração.
+use sqlite::ConnectionThreadSafe as Db;
+use std::fmt::Write as _;
+
+use crate::db::{transaction, DbResult, Room, RoomMsg, User};
+
+/// Preenche vagas livres da sala com a fila de espera, notificando os envolvidos.
+pub fn admit_queued(db: &Db, room: &Room, room_name: &str) -> DbResult<()> {
+    transaction(db, || notify_admitted(db, room, room_name))
+}
+
+fn notify_admitted(db: &Db, room: &Room, room_name: &str) -> DbResult<()> {
+    let mut msg = String::new();
+    for user_id in room.admit_queued(db)? {
+        let Some(user_name) = User::get_name(db, user_id)? else {
+            continue;
+        };
+        msg.clear();
+        let _ = writeln!(&mut msg, "ENTROU {} {}", room_name, user_name);
+        room.broadcast(db, &msg, user_id, 0)?;
+
+        msg.clear();
+        let _ = write!(&mut msg, "ADMITIDO_DA_FILA {}", room_name);
+        for member_name in room.get_users(db)? {
+            let _ = write!(&mut msg, " {}", member_name);
+        }
+        let _ = writeln!(&mut msg);
+        msg.push_str(&welcome(db, room, room_name)?);
+        User::send_to(db, user_id, &msg)?;
+    }
+    Ok(())
+}
+
+/// Remove e bane `banned_id` da sala, avisando a sala e o banido.
+pub fn ban_user(
+    db: &Db,
+    room: &Room,
+    room_name: &str,
+    banned_id: i64,
+    banned_name: &str,
+    actor_id: i64,
+) -> DbResult<()> {
+    transaction(db, || {
+        remove_banned(db, room, room_name, banned_id, banned_name, actor_id)
+    })
+}
+
+fn remove_banned(
+    db: &Db,
+    room: &Room,
+    room_name: &str,
+    banned_id: i64,
+    banned_name: &str,
+    actor_id: i64,
+) -> DbResult<()> {
+    let mut msg = String::new();
+    let kicked = room.kick(db, banned_id)?;
+    if kicked {
+        let _ = writeln!(&mut msg, "SAIU {} {}", room_name, banned_name);
+        room.broadcast(db, &msg, actor_id, banned_id)?;
+    }
+    if room.ban(db, banned_id)? {
+        msg.clear();
+        let _ = writeln!(&mut msg, "BANIDO_DA_SALA {}", room_name);
+        User::send_to(db, banned_id, &msg)?;
+    }
+    if kicked {
+        admit_queued(db, room, room_name)?;
+    }
+    Ok(())
+}
+
+pub fn close_room(db: &Db, room: &Room, room_name: &str, actor_id: i64) -> DbResult<()> {
+    let msg = format!("SALA_FECHADA {}\n", room_name);
+    transaction(db, || {
+        room.broadcast(db, &msg, actor_id, 0)?;
+        room.delete_cascade(db)
+    })
+}
+
+/// O que quem acaba de entrar na sala recebe depois da confirmação: `TOPICO`, se houver, e as
+/// mensagens fixadas.
+pub fn welcome(db: &Db, room: &Room, room_name: &str) -> DbResult<String> {
+    let mut msg = String::new();
+    let topic = room.get_topic(db)?;
+    if !topic.is_empty() {
+        let _ = writeln!(&mut msg, "TOPICO {} {}", room_name, topic);
+    }
+    msg.push_str(&pins_listing(room_name, &room.get_pins(db)?));
+    Ok(msg)
+}
+
+/// `FIXADAS sala n` seguido de uma linha `FIXADA` por mensagem fixada.
+pub fn pins_listing(room_name: &str, pins: &[RoomMsg]) -> String {
+    let mut msg = String::new();
+    let _ = writeln!(&mut msg, "FIXADAS {} {}", room_name, pins.len());
+    for pin in pins {
+        let _ = writeln!(
+            &mut msg,
+            "FIXADA {} {} {} {}",
+            room_name, pin.id, pin.user_name, pin.msg
+        );
+    }
+    msg
+}
@@ -0,0 +1,53 @@
+//! Encerramento gracioso com SIGINT/SIGTERM.
+//!
+//! O handler do sinal só marca `REQUESTED`. `main` percebe, para de aceitar conexões e chama
+//! `begin`; cada `handle_client` termina o comando em andamento, entrega o que falta de
+//! `drain_msgs`, avisa `SERVIDOR_ENCERRANDO <segundos>` e desconecta. Conexões ainda no
+//! handshake são fechadas sem aviso.
+//!
+//! Com `CHAT_DUMP_FILE` o banco é gravado ao sair, mas só como exportação para inspeção: nada
+//! o carrega de volta na inicialização.
+use async_std::task;
+use core::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+
+use crate::db;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+/// instante limite em ms, 0 = servidor não está encerrando
+static DEADLINE: AtomicI64 = AtomicI64::new(0);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    // um segundo sinal não espera mais ninguém
+    if REQUESTED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(130) };
+    }
+}
+
+pub fn install() {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// Espera até que um sinal de encerramento chegue.
+pub async fn requested() {
+    while !REQUESTED.load(Ordering::SeqCst) {
+        task::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+pub fn begin(deadline: Duration) {
+    let at = db::now_millis() + deadline.as_millis() as i64;
+    DEADLINE.store(at, Ordering::SeqCst);
+}
+
+/// Segundos até o prazo de encerramento, se o servidor estiver encerrando.
+pub fn remaining() -> Option<i64> {
+    match DEADLINE.load(Ordering::SeqCst) {
+        0 => None,
+        at => Some(((at - db::now_millis()).max(0) + 999) / 1000),
+    }
+}
@@ -4,7 +4,12 @@ use async_std::prelude::*;
 use core::time::Duration;
 use openssl::{base64, symm};
 use std::fmt::Write as _;
+use std::sync::atomic::Ordering;
 
+use crate::error::ErrorCode;
+use crate::i18n::{self, Language};
+use crate::log;
+use crate::metrics::METRICS;
 use crate::{AesKey, IoError};
 
 const READ_TIMEOUT: Duration = Duration::from_millis(500);
@@ -13,47 +18,61 @@ pub struct Stream {
     stream: BufReader<TcpStream>,
     cipher: symm::Cipher,
     aes_key: Option<AesKey>,
+    /// guardado na aceitação, o socket pode já estar desconectado quando for preciso
+    peer_addr: std::net::SocketAddr,
+    /// idioma dos textos de erro, escolhido no `REGISTRO`
+    language: &'static Language,
 }
 
 impl Drop for Stream {
     fn drop(&mut self) {
-        eprintln!(
-            "(SERVER)\tClosed connection {:?}",
-            self.stream.get_ref().peer_addr().unwrap()
-        );
+        METRICS.connections_open.fetch_sub(1, Ordering::Relaxed);
+        info!(Net, "Closed connection {:?}", self.peer_addr());
     }
 }
 
 impl Stream {
-    pub fn new(stream: TcpStream) -> Self {
-        eprintln!("(SERVER)\tNew connection {:?}", stream.peer_addr().unwrap());
+    pub fn new(stream: TcpStream, peer_addr: std::net::SocketAddr) -> Self {
+        METRICS.connections_open.fetch_add(1, Ordering::Relaxed);
         let stream = BufReader::new(stream);
         Self {
             stream,
             cipher: symm::Cipher::aes_256_ecb(),
             aes_key: None,
+            peer_addr,
+            language: i18n::default(),
         }
     }
 
+    pub fn set_language(&mut self, language: &'static Language) {
+        self.language = language;
+    }
+
+    pub fn language(&self) -> &'static Language {
+        self.language
+    }
+
     pub fn set_aes_key(&mut self, aes_key: AesKey) {
         self.aes_key = Some(aes_key);
     }
 
     pub fn peer_addr(&self) -> std::net::SocketAddr {
-        self.stream.get_ref().peer_addr().unwrap()
+        self.peer_addr
     }
 
+    #[allow(clippy::needless_lifetimes)]
     pub async fn block_read_plain_line<'a>(
         &mut self,
         buf: &'a mut String,
     ) -> Result<usize, IoError> {
         buf.clear();
-        self.stream
-            .read_line(buf)
-            .await
-            .map_err(|_| IoError::Closed)
+        match self.stream.read_line(buf).await {
+            Ok(0) | Err(_) => Err(IoError::Closed),
+            Ok(read) => Ok(read),
+        }
     }
 
+    #[allow(clippy::needless_lifetimes, clippy::needless_return)]
     pub async fn read_plain_line<'a>(&mut self, buf: &'a mut String) -> Result<(), IoError> {
         buf.clear();
         let read = async_std::io::timeout(READ_TIMEOUT, self.stream.read_line(buf)).await;
@@ -62,9 +81,9 @@ impl Stream {
             Err(err) if err.kind() == async_std::io::ErrorKind::TimedOut => {
                 return Err(IoError::Timeout);
             }
-            Err(_) => return Err(IoError::Closed),
+            Err(_) | Ok(0) => return Err(IoError::Closed),
             Ok(_) => {
-                eprintln!("(MSG)\t{buf:?}");
+                trace!(Net, "{}", log::Command(buf));
                 return Ok(());
             }
         }
@@ -90,21 +109,29 @@ impl Stream {
         let Ok(dec) = std::str::from_utf8(&dec) else {
             return Err(IoError::BadCrypto);
         };
-        eprintln!("(MSG)\t{dec:?}");
+        debug!(Protocol, "{}", log::Command(dec));
         buf.clear();
         let _ = writeln!(buf, "{}", dec);
         Ok(())
     }
 
     pub async fn write_plain_msg(&mut self, msg: &str) -> Result<(), IoError> {
+        // conexão caída: quem chamou encerra a sessão pelo caminho normal
         self.stream
             .get_mut()
             .write_all(msg.as_bytes())
             .await
-            .unwrap();
-        Ok(())
-        // .map(|_| ())
-        // .map_err(|_| IoError::Closed)
+            .map_err(|_| IoError::Closed)
+    }
+
+    pub async fn write_plain_error(&mut self, code: ErrorCode) -> Result<(), IoError> {
+        let msg = format!("{}\n", code.localized(self.language));
+        self.write_plain_msg(&msg).await
+    }
+
+    pub async fn write_error(&mut self, code: ErrorCode) -> Result<(), IoError> {
+        let msg = code.localized(self.language).to_string();
+        self.write_msg(&msg).await
     }
 
     pub async fn write_msg(&mut self, msg: &str) -> Result<(), IoError> {
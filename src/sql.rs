@@ -0,0 +1,330 @@
+//! SQL direto do console de administração.
+//!
+//! Cada consulta roda no banco vivo, com o lock global do banco, dentro de um savepoint que é
+//! desfeito no fim, então nada que o operador digite fica no banco. Sem `/sql-write on` a
+//! conexão fica em `query_only` durante a consulta; com ele o resultado da escrita serve de
+//! prévia, e ela só é mantida quando `/commit` a repete e altera o mesmo número de linhas.
+//!
+//! Os clientes esperam pelo lock enquanto a consulta roda, então ela é interrompida depois de
+//! `QUERY_TIMEOUT`.
+use sqlite::{ConnectionThreadSafe as Db, State, Value};
+use sqlite3_sys as ffi;
+use std::ffi::{c_int, c_void, CStr, CString};
+use std::fmt::Write as _;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+/// Linhas mostradas por consulta, o resto só é contado.
+const MAX_ROWS: usize = 200;
+
+/// Tempo máximo de uma instrução do console.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Instruções da VM do SQLite entre as checagens do prazo.
+const PROGRESS_STEPS: c_int = 10_000;
+
+/// Instruções que mexeriam no savepoint do console ou na conexão compartilhada.
+const FORBIDDEN: &[&str] = &[
+    "BEGIN",
+    "COMMIT",
+    "END",
+    "ROLLBACK",
+    "SAVEPOINT",
+    "RELEASE",
+    "ATTACH",
+    "DETACH",
+    "VACUUM",
+    "PRAGMA",
+];
+
+/// Estado do SQL de uma sessão do console.
+#[derive(Default)]
+pub struct SqlSession {
+    writes: bool,
+    /// escrita já vista na prévia, com as linhas que ela alterou, aguardando `/commit`
+    pending: Option<(String, usize)>,
+}
+
+impl SqlSession {
+    pub fn set_writes(&mut self, writes: bool, out: &mut String) {
+        self.writes = writes;
+        if !writes && self.pending.take().is_some() {
+            let _ = writeln!(out, "pending write discarded");
+        }
+        let mode = if writes {
+            "read-write (preview + /commit)"
+        } else {
+            "read-only"
+        };
+        let _ = writeln!(out, "SQL console is {}", mode);
+    }
+
+    pub fn query(&mut self, db: &Db, query: &str, out: &mut String) {
+        let query = query.trim();
+        let keyword = first_keyword(query);
+        if FORBIDDEN.contains(&keyword.as_str()) {
+            let _ = writeln!(out, "{} is not allowed in the console", keyword);
+            if keyword == "PRAGMA" {
+                let _ = writeln!(
+                    out,
+                    "(use the pragma functions, e.g. SELECT * FROM pragma_table_info('users'))"
+                );
+            }
+            return;
+        }
+        let readonly = match inspect(db, query) {
+            Ok(readonly) => readonly,
+            Err(err) => {
+                let _ = writeln!(out, "{}", err);
+                return;
+            }
+        };
+        if !self.writes && !readonly {
+            let _ = writeln!(out, "console is read-only, /sql-write on to allow writes");
+            return;
+        }
+        let changes = match execute(db, query, out, !self.writes, |_| false) {
+            Ok((changes, _)) => changes,
+            Err(err) => {
+                print_error(out, &err);
+                return;
+            }
+        };
+        if self.writes && changes > 0 {
+            let _ = writeln!(out, "preview: {} rows would change", changes);
+            let _ = writeln!(
+                out,
+                "/commit to apply it to the live database, /rollback to discard"
+            );
+            self.pending = Some((query.to_string(), changes));
+        }
+    }
+
+    /// Aplica a escrita pendente, devolvendo o SQL se deu certo.
+    pub fn commit(&mut self, db: &Db, out: &mut String) -> Option<String> {
+        let Some((query, previewed)) = self.pending.take() else {
+            let _ = writeln!(out, "nothing to commit");
+            return None;
+        };
+        match execute(db, &query, out, false, |changes| changes == previewed) {
+            Ok((changes, true)) => {
+                let _ = writeln!(out, "committed: {} rows changed", changes);
+                Some(query)
+            }
+            Ok((changes, false)) => {
+                let _ = writeln!(
+                    out,
+                    "database changed since the preview ({} rows now, {} previewed), discarded",
+                    changes, previewed
+                );
+                let _ = writeln!(out, "run the query again to see a new preview");
+                None
+            }
+            Err(err) => {
+                print_error(out, &err);
+                None
+            }
+        }
+    }
+
+    pub fn rollback(&mut self, out: &mut String) {
+        if self.pending.take().is_some() {
+            let _ = writeln!(out, "discarded");
+        } else {
+            let _ = writeln!(out, "nothing to roll back");
+        }
+    }
+}
+
+/// Primeira palavra da consulta, pulando espaços e comentários.
+fn first_keyword(query: &str) -> String {
+    let mut rest = query;
+    loop {
+        rest = rest.trim_start();
+        if let Some(comment) = rest.strip_prefix("--") {
+            rest = comment.split_once('\n').map_or("", |(_, after)| after);
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            rest = comment.split_once("*/").map_or("", |(_, after)| after);
+        } else {
+            break;
+        }
+    }
+    rest.chars()
+        .take_while(char::is_ascii_alphabetic)
+        .collect::<String>()
+        .to_ascii_uppercase()
+}
+
+/// Confere que `query` é uma única instrução e diz se ela só lê o banco.
+fn inspect(db: &Db, query: &str) -> Result<bool, String> {
+    let sql = CString::new(query).map_err(|_| "NUL byte in query".to_string())?;
+    let _lock = crate::db::lock();
+    let raw = db.as_raw();
+    let mut statement = ptr::null_mut();
+    let mut tail = ptr::null();
+    // SAFETY: `raw` vive enquanto `db`, `sql` e `tail` (que aponta para dentro de `sql`) vivem
+    // até o fim do bloco, e toda instrução preparada é finalizada.
+    unsafe {
+        if ffi::sqlite3_prepare_v2(raw, sql.as_ptr(), -1, &mut statement, &mut tail)
+            != ffi::SQLITE_OK
+        {
+            let err = CStr::from_ptr(ffi::sqlite3_errmsg(raw));
+            return Err(format!("SQL error: {}", err.to_string_lossy()));
+        }
+        if statement.is_null() {
+            return Err("empty query".to_string());
+        }
+        let readonly = ffi::sqlite3_stmt_readonly(statement) != 0;
+        ffi::sqlite3_finalize(statement);
+        // depois da primeira instrução só pode sobrar espaço, `;` e comentários, que não
+        // compilam para nada
+        let mut next = ptr::null_mut();
+        let mut rest = ptr::null();
+        let rc = ffi::sqlite3_prepare_v2(raw, tail, -1, &mut next, &mut rest);
+        let single = rc == ffi::SQLITE_OK && next.is_null();
+        ffi::sqlite3_finalize(next);
+        if !single {
+            return Err("one statement at a time".to_string());
+        }
+        Ok(readonly)
+    }
+}
+
+/// Roda `query` num savepoint do banco vivo, imprimindo o resultado. A escrita só é mantida se
+/// `keep` aceitar o número de linhas alteradas; devolve esse número e se ela foi mantida.
+fn execute(
+    db: &Db,
+    query: &str,
+    out: &mut String,
+    readonly: bool,
+    keep: impl FnOnce(usize) -> bool,
+) -> sqlite::Result<(usize, bool)> {
+    let _lock = crate::db::lock();
+    let _limits = Limits::install(db, readonly)?;
+    db.execute("SAVEPOINT console")?;
+    let before = db.total_change_count();
+    let result = print_query(db, query, out).map(|()| db.total_change_count() - before);
+    let kept = matches!(result, Ok(changes) if keep(changes));
+    // uma escrita interrompida pode já ter desfeito o savepoint inteiro
+    // SAFETY: `db` é uma conexão aberta
+    if unsafe { ffi::sqlite3_get_autocommit(db.as_raw()) } == 0 {
+        if !kept {
+            db.execute("ROLLBACK TO console")?;
+        }
+        db.execute("RELEASE console")?;
+    }
+    result.map(|changes| (changes, kept))
+}
+
+/// Prazo e `query_only` na conexão compartilhada, desfeitos no drop. Só com o lock do banco.
+struct Limits<'a> {
+    db: &'a Db,
+    readonly: bool,
+    /// lido pelo progress handler, por isso numa caixa com endereço fixo
+    _deadline: Box<Instant>,
+}
+
+impl<'a> Limits<'a> {
+    fn install(db: &'a Db, readonly: bool) -> sqlite::Result<Self> {
+        let deadline = Box::new(Instant::now() + QUERY_TIMEOUT);
+        // SAFETY: o handler é removido no drop, antes de a caixa ser liberada
+        unsafe {
+            let arg = &*deadline as *const Instant as *mut c_void;
+            ffi::sqlite3_progress_handler(db.as_raw(), PROGRESS_STEPS, Some(past_deadline), arg);
+        }
+        let limits = Limits {
+            db,
+            readonly,
+            _deadline: deadline,
+        };
+        if readonly {
+            db.execute("PRAGMA query_only = ON")?;
+        }
+        Ok(limits)
+    }
+}
+
+impl Drop for Limits<'_> {
+    fn drop(&mut self) {
+        if self.readonly {
+            let _ = self.db.execute("PRAGMA query_only = OFF");
+        }
+        // SAFETY: `db` é uma conexão aberta
+        unsafe {
+            ffi::sqlite3_progress_handler(self.db.as_raw(), 0, None, ptr::null_mut());
+        }
+    }
+}
+
+unsafe extern "C" fn past_deadline(deadline: *mut c_void) -> c_int {
+    // SAFETY: aponta para o `Instant` de `Limits`, vivo enquanto o handler está instalado
+    let deadline = unsafe { &*(deadline as *const Instant) };
+    (Instant::now() >= *deadline) as c_int
+}
+
+fn print_error(out: &mut String, err: &sqlite::Error) {
+    if err.code == Some(ffi::SQLITE_INTERRUPT as isize) {
+        let _ = writeln!(
+            out,
+            "interrupted after {}s, clients wait while a console query runs",
+            QUERY_TIMEOUT.as_secs()
+        );
+    } else {
+        let _ = writeln!(out, "SQL error: {}", err);
+    }
+}
+
+/// Imprime o resultado como tabela alinhada, com a contagem de linhas.
+fn print_query(conn: &Db, query: &str, out: &mut String) -> sqlite::Result<()> {
+    let mut statement = conn.prepare(query)?;
+    let names = statement.column_names().to_vec();
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    let mut total = 0;
+    while statement.next()? == State::Row {
+        total += 1;
+        if rows.len() == MAX_ROWS {
+            continue;
+        }
+        let mut row = Vec::with_capacity(names.len());
+        for i in 0..names.len() {
+            row.push(match statement.read::<Value, _>(i)? {
+                Value::Null => "NULL".to_string(),
+                Value::Integer(value) => value.to_string(),
+                Value::Float(value) => value.to_string(),
+                Value::String(value) => value,
+                Value::Binary(value) => format!("<{} bytes>", value.len()),
+            });
+        }
+        rows.push(row);
+    }
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    let mut widths: Vec<usize> = names.iter().map(|name| name.chars().count()).collect();
+    for row in &rows {
+        for (width, value) in widths.iter_mut().zip(row) {
+            *width = (*width).max(value.chars().count());
+        }
+    }
+    let print_row = |out: &mut String, row: &[String]| {
+        for (i, (value, width)) in row.iter().zip(&widths).enumerate() {
+            let sep = if i == 0 { "" } else { " | " };
+            let _ = write!(out, "{}{:<width$}", sep, value, width = width);
+        }
+        out.truncate(out.trim_end_matches(' ').len());
+        let _ = writeln!(out);
+    };
+    print_row(out, &names);
+    let rule: Vec<String> = widths.iter().map(|&width| "-".repeat(width)).collect();
+    let _ = writeln!(out, "{}", rule.join("-+-"));
+    for row in &rows {
+        print_row(out, row);
+    }
+    if total > rows.len() {
+        let _ = writeln!(out, "... ({} rows, {} shown)", total, rows.len());
+    } else {
+        let _ = writeln!(out, "({} rows)", total);
+    }
+    Ok(())
+}